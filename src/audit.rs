@@ -0,0 +1,721 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypt::{read_key_file, KEY_SIZE};
+use crate::digest::to_hex;
+use crate::error::{BackupError, Result};
+use crate::logfile;
+use crate::sinks::{self, LogSink};
+use crate::repository::Repository;
+
+/// How entries are written to the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One JSON object per line, with the fields of [`AuditEntry`] and who
+    /// made the change, for log collectors.
+    #[default]
+    Json,
+    /// The original `[YYYY-MM-DD HH:MM:SS] message` lines.
+    Text,
+}
+
+impl LogFormat {
+    /// Parses `json` or `text`.
+    pub fn parse(text: &str) -> Option<LogFormat> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "json" | "jsonl" => Some(LogFormat::Json),
+            "text" => Some(LogFormat::Text),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            LogFormat::Text => "text",
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What one audit entry records about an operation.
+#[derive(Debug, Clone)]
+pub(crate) struct AuditEntry {
+    /// `backup`, `restore`, `delete`, `verify`, `prune` or `recover`; for
+    /// a rejected name, whatever the caller of [`log_rejected`] gives.
+    pub operation: &'static str,
+    /// The file or directory operated on; the repository for commands that
+    /// cover all of it.
+    pub target: PathBuf,
+    /// `success`, `failed`, `cancelled` (declined at a prompt) or `rejected`
+    /// (an invalid name, refused before anything was touched).
+    pub result: &'static str,
+    /// Why an operation did not succeed: the error it ended with.
+    pub reason: Option<String>,
+    /// Bytes backed up, restored, deleted or freed.
+    pub bytes: Option<u64>,
+    /// Hex SHA-256 of the data backed up or restored.
+    pub digest: Option<String>,
+    pub generation: Option<u64>,
+    /// The line the text format writes, e.g. `Performed backup on notes.txt
+    /// (generation 3)`.
+    pub message: String,
+}
+
+impl AuditEntry {
+    /// An entry for an operation on `target` that succeeded.
+    pub(crate) fn success(operation: &'static str, target: &Path, message: String) -> AuditEntry {
+        AuditEntry {
+            operation,
+            target: target.to_path_buf(),
+            result: "success",
+            reason: None,
+            bytes: None,
+            digest: None,
+            generation: None,
+            message,
+        }
+    }
+
+    /// An entry for an attempt at `operation` on `target` that ended in
+    /// `error`, e.g. `Failed backup on notes.txt: File not found: notes.txt`.
+    pub(crate) fn failure(operation: &'static str, target: &Path, error: &BackupError) -> AuditEntry {
+        let (result, verb) = match error {
+            BackupError::Cancelled => ("cancelled", "Cancelled"),
+            BackupError::InvalidName(_) => ("rejected", "Rejected"),
+            _ => ("failed", "Failed"),
+        };
+        AuditEntry {
+            operation,
+            target: target.to_path_buf(),
+            result,
+            reason: Some(error.to_string()),
+            bytes: None,
+            digest: None,
+            generation: None,
+            message: format!("{} {} on {}: {}", verb, operation, target.display(), error),
+        }
+    }
+}
+
+/// `prev` of the first entry of a chain.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An [`AuditEntry`] with when, by whom and where, as every sink gets it.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Record {
+    /// RFC 3339 with milliseconds and the UTC offset.
+    pub timestamp: String,
+    pub operation: &'static str,
+    /// Absolute path of the target.
+    pub target: String,
+    pub result: &'static str,
+    pub reason: Option<String>,
+    pub bytes: Option<u64>,
+    pub digest: Option<String>,
+    pub generation: Option<u64>,
+    pub message: String,
+    /// Login name, or the uid when it has none.
+    pub user: String,
+    pub uid: u32,
+    pub pid: u32,
+    pub hostname: String,
+}
+
+impl Record {
+    fn new(entry: &AuditEntry, now: DateTime<Local>) -> Record {
+        let target = std::path::absolute(&entry.target).unwrap_or_else(|_| entry.target.clone());
+        // SAFETY: getuid has no preconditions and cannot fail.
+        let uid = unsafe { libc::getuid() };
+        Record {
+            timestamp: now.to_rfc3339_opts(SecondsFormat::Millis, false),
+            operation: entry.operation,
+            target: target.to_string_lossy().into_owned(),
+            result: entry.result,
+            reason: entry.reason.clone(),
+            bytes: entry.bytes,
+            digest: entry.digest.clone(),
+            generation: entry.generation,
+            message: entry.message.clone(),
+            user: user_name(uid).unwrap_or_else(|| uid.to_string()),
+            uid,
+            pid: process::id(),
+            hostname: hostname().unwrap_or_default(),
+        }
+    }
+}
+
+/// The JSON form of an entry in the log file, without its `hash`.
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    record: &'a Record,
+    chain: &'a str,
+    prev: &'a str,
+}
+
+/// How the `hash` of every JSON entry is computed, over the entry's text
+/// without it. Each entry carries the hash of the one before it in `prev`,
+/// so removing, reordering or editing any entry breaks the chain.
+enum Chain {
+    Sha256,
+    /// HMAC-SHA-256 with the key in `log_key_file`, so that someone who can
+    /// write the log but lacks the key cannot rebuild the chain either.
+    Hmac([u8; KEY_SIZE]),
+}
+
+impl Chain {
+    fn new(key: Option<[u8; KEY_SIZE]>) -> Chain {
+        key.map_or(Chain::Sha256, Chain::Hmac)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Chain::Sha256 => "sha256",
+            Chain::Hmac(_) => "hmac-sha256",
+        }
+    }
+
+    fn hash(&self, body: &str) -> String {
+        match self {
+            Chain::Sha256 => to_hex(&Sha256::digest(body.as_bytes())),
+            Chain::Hmac(key) => {
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+                mac.update(body.as_bytes());
+                to_hex(&mac.finalize().into_bytes())
+            }
+        }
+    }
+}
+
+/// A JSON entry as read back from the log.
+struct Link {
+    seq: u64,
+    chain: String,
+    prev: String,
+    hash: String,
+    /// The entry's text without its `hash`, as it was hashed.
+    body: String,
+}
+
+impl Link {
+    /// Parses a line written by [`log_action`] in the JSON format; `None`
+    /// for anything else, including JSON entries from before the chain.
+    fn parse(line: &str) -> Option<Link> {
+        #[derive(Deserialize)]
+        struct Fields {
+            seq: u64,
+            chain: String,
+            prev: String,
+        }
+
+        // Quotes inside JSON strings are escaped, so the last `,"hash":"`
+        // is always the field itself.
+        let (rest, hash) = line.strip_suffix("\"}")?.rsplit_once(",\"hash\":\"")?;
+        if hash.len() != GENESIS.len() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let body = format!("{}}}", rest);
+        let fields: Fields = serde_json::from_str(&body).ok()?;
+        Some(Link {
+            seq: fields.seq,
+            chain: fields.chain,
+            prev: fields.prev,
+            hash: hash.to_string(),
+            body,
+        })
+    }
+}
+
+/// Hands `entry` to every sink in the repository's `log_sinks`. Each sink is
+/// tried even when one before it failed; the first failure is returned.
+pub(crate) fn log_action(repo: &Repository, entry: &AuditEntry) -> io::Result<()> {
+    let settings = repo.settings();
+    let now = Local::now();
+    let record = Record::new(entry, now);
+    let mut result = Ok(());
+    for sink in &settings.log_sinks {
+        let sent = match sink {
+            LogSink::File => write_file(repo, &record, now),
+            LogSink::Syslog => sinks::send_syslog(&record, settings.syslog_facility),
+            LogSink::Journald => sinks::send_journald(&record),
+        };
+        result = result.and(sent);
+    }
+    result
+}
+
+/// Passes `result` on, first logging it as a failed, cancelled or rejected
+/// attempt at `operation` if it is an error; a success is logged by the
+/// operation itself, with details only it knows. An error from the audit
+/// log itself is not logged again, and failing to log an error gives way to
+/// the error being logged.
+pub(crate) fn audited<T>(repo: &Repository, operation: &'static str, target: &Path, result: Result<T>) -> Result<T> {
+    if let Err(error) = &result
+        && !matches!(error, BackupError::Log(_))
+    {
+        let _ = log_action(repo, &AuditEntry::failure(operation, target, error));
+    }
+    result
+}
+
+/// Records that `operation` on `filename` was refused before it started,
+/// for callers that check names or open the repository themselves: an
+/// invalid name from [`validate_filename`](crate::validate_filename) may be
+/// someone probing for path traversal. `repo` may be
+/// [`Repository::unopened`] when no repository could be opened.
+pub fn log_rejected(repo: &Repository, operation: &'static str, filename: &str, error: &BackupError) -> Result<()> {
+    log_action(repo, &AuditEntry::failure(operation, Path::new(filename), error)).map_err(BackupError::Log)
+}
+
+/// Appends `record` to the audit log file, in the repository's
+/// `log_format`. A JSON entry is chained to the last one in the log.
+fn write_file(repo: &Repository, record: &Record, now: DateTime<Local>) -> io::Result<()> {
+    let settings = repo.settings();
+    let key = match settings.log_format {
+        LogFormat::Json => settings
+            .log_key
+            .as_deref()
+            .map(|path| read_key_file(path, "log_key_file"))
+            .transpose()
+            .map_err(io::Error::other)?,
+        LogFormat::Text => None,
+    };
+
+    logfile::append(repo, |last| {
+        if settings.log_format == LogFormat::Text {
+            let sanitized = record.message.replace("\n", " ").replace("\r", " ");
+            return Ok(format!("[{}] {}", now.format("%Y-%m-%d %H:%M:%S"), sanitized));
+        }
+
+        let chain = Chain::new(key);
+        let (seq, prev) = match last.and_then(Link::parse) {
+            Some(link) => (link.seq + 1, link.hash),
+            None => (1, GENESIS.to_string()),
+        };
+        // serde_json escapes control characters, so one entry is always one
+        // line.
+        let body = serde_json::to_string(&JsonEntry {
+            seq,
+            record,
+            chain: chain.name(),
+            prev: &prev,
+        })
+        .map_err(io::Error::other)?;
+        let hash = chain.hash(&body);
+        Ok(format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], hash))
+    })
+}
+
+/// One break in the audit log's chain found by [`verify_log`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogProblem {
+    /// The segment or live log the line is in.
+    pub file: PathBuf,
+    /// 1-based line number in that file.
+    pub line: usize,
+    /// The entry's `seq`, if the line is a chained entry at all.
+    pub seq: Option<u64>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogVerifyReport {
+    /// Rotated segments, oldest first, then the live log.
+    pub files: Vec<PathBuf>,
+    /// Chained entries checked.
+    pub entries: usize,
+    /// Lines before the chain starts, written before it existed or in the
+    /// text format; nothing vouches for them.
+    pub unchained: usize,
+    pub problems: Vec<LogProblem>,
+    /// `seq` of the first entry, when older segments were deleted by
+    /// `log_keep` and the chain starts later than 1.
+    pub first: Option<u64>,
+    /// `seq` of the first entry keyed with HMAC. With the key set, no plain
+    /// SHA-256 entry may follow it: anyone can compute those.
+    pub keyed_from: Option<u64>,
+    /// `seq` and `hash` of the last entry. Keeping a copy elsewhere also
+    /// shows when entries are cut off the end of the log.
+    pub head: Option<(u64, String)>,
+}
+
+impl LogVerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Walks the hash chain through every rotated segment of the audit log and
+/// the live log, and reports every entry that was altered, every gap left
+/// by removed entries and every entry out of order. Entries keyed with HMAC
+/// need the repository's `log_key_file`.
+pub fn verify_log(repo: &Repository) -> Result<LogVerifyReport> {
+    let key = match &repo.settings().log_key {
+        Some(key_path) => Some(read_key_file(key_path, "log_key_file")?),
+        None => None,
+    };
+    let keyed = Chain::new(key);
+
+    let path = repo.log_path();
+    // Writers hold it exclusively, so no entry is read half-written and no
+    // segment is renamed while it is read.
+    let _lock = logfile::lock(repo, &path, false).map_err(|e| BackupError::io(&path, e))?;
+    let files = logfile::files(&path).map_err(|e| BackupError::io(&path, e))?;
+    if files.is_empty() {
+        return Err(BackupError::NotFound(path));
+    }
+
+    let mut report = LogVerifyReport {
+        files: files.iter().map(|(path, _)| path.clone()).collect(),
+        entries: 0,
+        unchained: 0,
+        problems: Vec::new(),
+        first: None,
+        keyed_from: None,
+        head: None,
+    };
+    for (file, codec) in &files {
+        let text = logfile::read(file, *codec).map_err(|e| BackupError::io(file, e))?;
+        let lines = text.strip_suffix(b"\n").unwrap_or(&text);
+        for (i, line) in lines.split(|&b| b == b'\n').enumerate() {
+            if text.is_empty() {
+                break;
+            }
+            check_line(repo, &keyed, file, i + 1, &String::from_utf8_lossy(line), &mut report);
+        }
+    }
+    Ok(report)
+}
+
+/// Checks one line of the log against the chain so far in `report`.
+fn check_line(repo: &Repository, keyed: &Chain, file: &Path, line: usize, text: &str, report: &mut LogVerifyReport) {
+    let mut problem = |seq: Option<u64>, reason: String| {
+        report.problems.push(LogProblem {
+            file: file.to_path_buf(),
+            line,
+            seq,
+            reason,
+        });
+    };
+    let Some(link) = Link::parse(text) else {
+        if report.head.is_none() {
+            report.unchained += 1;
+        } else {
+            problem(None, "not a chained entry: inserted, edited or cut short".to_string());
+        }
+        return;
+    };
+    report.entries += 1;
+    let seq = Some(link.seq);
+
+    if link.chain == "hmac-sha256" && report.keyed_from.is_none() {
+        report.keyed_from = Some(link.seq);
+    }
+    let expected = match link.chain.as_str() {
+        "sha256" if matches!(keyed, Chain::Hmac(_)) && report.keyed_from.is_some() => {
+            problem(seq, "not keyed with HMAC although earlier entries are: forged or downgraded".to_string());
+            None
+        }
+        "sha256" => Some(Chain::Sha256.hash(&link.body)),
+        "hmac-sha256" if matches!(keyed, Chain::Hmac(_)) => Some(keyed.hash(&link.body)),
+        "hmac-sha256" => {
+            problem(seq, "keyed with HMAC; set log_key_file or pass --log-key-file to check it".to_string());
+            None
+        }
+        other => {
+            problem(seq, format!("unknown chain '{}'", other));
+            None
+        }
+    };
+    if expected.is_some_and(|expected| expected != link.hash) {
+        problem(seq, "altered: the hash does not match the contents".to_string());
+    }
+
+    match &report.head {
+        None if link.seq == 1 && link.prev == GENESIS => {}
+        // Segments deleted by `log_keep` take the start of the chain with
+        // them.
+        None if repo.settings().log_rotation.keep.is_some() => report.first = Some(link.seq),
+        None => problem(seq, "the chain does not start here: earlier entries were removed".to_string()),
+        Some(_) if link.seq == 1 && link.prev == GENESIS => {
+            problem(seq, "the chain starts over: earlier entries were removed or replaced".to_string())
+        }
+        Some((_, hash)) if link.prev != *hash => problem(
+            seq,
+            "does not follow the entry before it: entries were removed, reordered or altered".to_string(),
+        ),
+        Some((prev_seq, _)) if link.seq != prev_seq + 1 => {
+            problem(seq, format!("sequence jumps from {} to {}", prev_seq, link.seq))
+        }
+        Some(_) => {}
+    }
+    report.head = Some((link.seq, link.hash));
+}
+
+/// The login name of `uid`, if the password database has one.
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+        let mut found = std::ptr::null_mut();
+        // SAFETY: every pointer refers to live storage of the given size.
+        let status = unsafe {
+            libc::getpwuid_r(uid, passwd.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len(), &mut found)
+        };
+        if status == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if status != 0 || found.is_null() {
+            return None;
+        }
+        // SAFETY: on success `pw_name` points to a NUL-terminated string in
+        // `buffer`.
+        let name = unsafe { CStr::from_ptr((*found).pw_name) };
+        return Some(name.to_string_lossy().into_owned());
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buffer = [0 as libc::c_char; 256];
+    // SAFETY: the buffer is writable for its whole length.
+    if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len() - 1) } != 0 {
+        return None;
+    }
+    // SAFETY: the last byte is never written, so the name is NUL-terminated.
+    let name = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// A new repository in a fresh directory under the system temp
+    /// directory, logging to `audit.log` inside it, optionally keyed with
+    /// `key`.
+    fn scratch_repo(key: Option<&[u8; KEY_SIZE]>) -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_audit_{}_{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut repo = Repository::init(&dir).unwrap();
+        repo.settings_mut().log_path = Some(PathBuf::from("audit.log"));
+        if let Some(key) = key {
+            let key_path = dir.join("log.key");
+            fs::write(&key_path, key).unwrap();
+            repo.settings_mut().log_key = Some(key_path);
+        }
+        repo
+    }
+
+    /// Logs four backups and returns the log's lines.
+    fn write_entries(repo: &Repository) -> Vec<String> {
+        for n in 1..=4 {
+            let message = format!("Performed backup on notes-{}.txt", n);
+            log_action(repo, &AuditEntry::success("backup", Path::new(&format!("notes-{}.txt", n)), message)).unwrap();
+        }
+        read_lines(repo)
+    }
+
+    fn read_lines(repo: &Repository) -> Vec<String> {
+        fs::read_to_string(repo.log_path()).unwrap().lines().map(str::to_string).collect()
+    }
+
+    fn write_lines(repo: &Repository, lines: &[String]) {
+        fs::write(repo.log_path(), lines.join("\n") + "\n").unwrap();
+    }
+
+    /// Line numbers and reasons of the problems `verify_log` finds.
+    fn problems(repo: &Repository) -> Vec<(usize, String)> {
+        let report = verify_log(repo).unwrap();
+        report.problems.into_iter().map(|p| (p.line, p.reason)).collect()
+    }
+
+    fn cleanup(repo: Repository) {
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let repo = scratch_repo(None);
+        write_entries(&repo);
+
+        let report = verify_log(&repo).unwrap();
+
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.head.map(|(seq, _)| seq), Some(4));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn deleted_line_is_reported() {
+        let repo = scratch_repo(None);
+        let mut lines = write_entries(&repo);
+        lines.remove(1);
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, 2);
+        assert!(problems[0].1.contains("does not follow the entry before it"));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn reordered_lines_are_reported() {
+        let repo = scratch_repo(None);
+        let mut lines = write_entries(&repo);
+        lines.swap(1, 2);
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        let lines: Vec<usize> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+        assert!(problems.iter().all(|(_, reason)| reason.contains("does not follow the entry before it")));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn edited_line_is_reported() {
+        let repo = scratch_repo(None);
+        let mut lines = write_entries(&repo);
+        lines[2] = lines[2].replace("notes-3.txt", "notes-9.txt");
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, 3);
+        assert!(problems[0].1.starts_with("altered"));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn edited_line_with_its_hash_recomputed_breaks_the_next_link() {
+        let repo = scratch_repo(None);
+        let mut lines = write_entries(&repo);
+        let link = Link::parse(&lines[2]).unwrap();
+        let body = link.body.replace("notes-3.txt", "notes-9.txt");
+        let hash = Chain::Sha256.hash(&body);
+        lines[2] = format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], hash);
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, 4);
+        cleanup(repo);
+    }
+
+    #[test]
+    fn inserted_lines_are_reported() {
+        let repo = scratch_repo(None);
+        let mut lines = write_entries(&repo);
+        // A second copy of entry 2, and a line that is no entry at all.
+        let copy = lines[1].clone();
+        lines.insert(2, copy);
+        lines.insert(4, "[2025-07-20 12:00:00] Performed backup on notes.txt".to_string());
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        let lines: Vec<usize> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 5]);
+        assert!(problems[1].1.starts_with("not a chained entry"));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn hmac_chain_needs_the_key() {
+        let key = [7u8; KEY_SIZE];
+        let repo = scratch_repo(Some(&key));
+        write_entries(&repo);
+        assert!(verify_log(&repo).unwrap().is_clean());
+
+        let mut without_key = repo.clone();
+        without_key.settings_mut().log_key = None;
+        let unkeyed = problems(&without_key);
+        assert_eq!(unkeyed.len(), 4);
+        assert!(unkeyed.iter().all(|(_, reason)| reason.starts_with("keyed with HMAC")));
+
+        let mut wrong_key = repo.clone();
+        let wrong_path = repo.root().join("wrong.key");
+        fs::write(&wrong_path, [8u8; KEY_SIZE]).unwrap();
+        wrong_key.settings_mut().log_key = Some(wrong_path);
+        let miskeyed = problems(&wrong_key);
+        assert_eq!(miskeyed.len(), 4);
+        assert!(miskeyed.iter().all(|(_, reason)| reason.starts_with("altered")));
+        cleanup(repo);
+    }
+
+    #[test]
+    fn entries_downgraded_from_hmac_are_reported() {
+        let key = [7u8; KEY_SIZE];
+        let repo = scratch_repo(Some(&key));
+        let mut lines = write_entries(&repo);
+        // Entries 3 and 4 rewritten as a valid plain SHA-256 chain, as
+        // someone without the key could.
+        let mut prev = Link::parse(&lines[1]).unwrap().hash;
+        for line in &mut lines[2..] {
+            let link = Link::parse(line).unwrap();
+            let body = link
+                .body
+                .replace("hmac-sha256", "sha256")
+                .replace(&link.prev, &prev)
+                .replace("notes-", "forged-");
+            prev = Chain::Sha256.hash(&body);
+            *line = format!("{},\"hash\":\"{}\"}}", &body[..body.len() - 1], prev);
+        }
+        write_lines(&repo, &lines);
+
+        let problems = problems(&repo);
+
+        let lines: Vec<usize> = problems.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 4]);
+        assert!(problems.iter().all(|(_, reason)| reason.starts_with("not keyed with HMAC")));
+        assert_eq!(verify_log(&repo).unwrap().keyed_from, Some(1));
+
+        cleanup(repo);
+    }
+
+    #[test]
+    fn plain_entries_before_the_key_was_set_verify() {
+        let key = [7u8; KEY_SIZE];
+        let repo = scratch_repo(Some(&key));
+        let mut plain = repo.clone();
+        plain.settings_mut().log_key = None;
+        write_entries(&plain);
+        write_entries(&repo);
+
+        let report = verify_log(&repo).unwrap();
+
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.keyed_from, Some(5));
+        cleanup(repo);
+    }
+}
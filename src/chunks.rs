@@ -0,0 +1,242 @@
+use std::fs;
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::codec::{self, Codec, Compression};
+use crate::crypt::SealingKey;
+use crate::digest::to_hex;
+use crate::error::{BackupError, Result};
+use crate::files::{create_dir_all, write_atomically};
+use crate::repository::Repository;
+
+/// Bounds for content-defined chunking. Cut points depend only on the bytes
+/// around them, so an edit changes the chunks it touches instead of shifting
+/// every chunk after it.
+pub(crate) const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+pub(crate) const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+pub(crate) const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Every chunk file starts with this magic, its codec tag and an
+/// encrypted flag.
+const MAGIC: &[u8; 4] = b"SBC1";
+const HEADER_SIZE: usize = 6;
+
+/// One piece of a chunked generation, in file order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkRef {
+    /// Hex SHA-256 of the chunk's data, or its keyed HMAC when encrypted.
+    pub id: String,
+    /// Length of the chunk's original data.
+    pub size: u64,
+}
+
+/// Whether `id` has the shape of a chunk id, so a damaged record can never
+/// name a path outside the chunk store.
+pub(crate) fn is_chunk_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The repository's chunk store, opened with the settings of one operation.
+pub(crate) struct ChunkStore<'a> {
+    repo: &'a Repository,
+    compression: Compression,
+    key: Option<SealingKey>,
+}
+
+impl<'a> ChunkStore<'a> {
+    pub(crate) fn new(repo: &'a Repository, compression: Compression, key: Option<SealingKey>) -> Self {
+        ChunkStore { repo, compression, key }
+    }
+
+    fn id_of(&self, data: &[u8]) -> String {
+        match &self.key {
+            Some(key) => key.chunk_id(data),
+            None => to_hex(&Sha256::digest(data)),
+        }
+    }
+
+    /// Stores `data` unless a chunk with the same id is already present, and
+    /// returns its reference with the number of bytes newly written (0 when
+    /// the chunk was already stored).
+    pub(crate) fn put(&self, data: &[u8]) -> Result<(ChunkRef, u64)> {
+        let chunk = ChunkRef {
+            id: self.id_of(data),
+            size: data.len() as u64,
+        };
+        let path = self.repo.chunk_path(&chunk.id);
+        if path.exists() {
+            return Ok((chunk, 0));
+        }
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir, self.repo.settings().dir_mode, self.repo.settings().durability)?;
+        }
+
+        let mut encoder = self.compression.encoder(Vec::new()).map_err(|e| BackupError::io(&path, e))?;
+        encoder.write_all(data).map_err(|e| BackupError::io(&path, e))?;
+        let compressed = encoder.finish().map_err(|e| BackupError::io(&path, e))?;
+        // Data that does not shrink is kept as is.
+        let (codec, payload) = if compressed.len() < data.len() {
+            (self.compression.codec, compressed)
+        } else {
+            (Codec::None, data.to_vec())
+        };
+
+        let mut bytes = header(codec, self.key.is_some()).to_vec();
+        match &self.key {
+            Some(key) => {
+                let sealed = key
+                    .seal_chunk(&aad(&chunk.id, &bytes), &payload)
+                    .map_err(|e| BackupError::io(&path, e))?;
+                bytes.extend_from_slice(&sealed);
+            }
+            None => bytes.extend_from_slice(&payload),
+        }
+
+        let settings = self.repo.settings();
+        write_atomically(&path, settings.file_mode, settings.durability, |file, tmp_path| {
+            file.write_all(&bytes).map_err(|e| BackupError::io(tmp_path, e))
+        })?;
+        Ok((chunk, bytes.len() as u64))
+    }
+
+    /// Reads `chunk` back, checking its length and id so a damaged or swapped
+    /// chunk is never returned.
+    pub(crate) fn get(&self, chunk: &ChunkRef) -> Result<Vec<u8>> {
+        let path = self.repo.chunk_path(&chunk.id);
+        let stored = fs::read(&path).map_err(|e| BackupError::io(&path, e))?;
+        let malformed = || BackupError::io(&path, io::Error::new(io::ErrorKind::InvalidData, "malformed chunk"));
+        // Records are checked when read, but the size also sizes a buffer.
+        if chunk.size > MAX_CHUNK_SIZE as u64 {
+            return Err(malformed());
+        }
+        if stored.len() < HEADER_SIZE || &stored[..MAGIC.len()] != MAGIC {
+            return Err(malformed());
+        }
+        let (header, body) = stored.split_at(HEADER_SIZE);
+        let codec = Codec::from_tag(header[4]).ok_or_else(malformed)?;
+
+        let opened;
+        let payload = match (header[5], &self.key) {
+            (0, None) => body,
+            (1, Some(key)) => {
+                opened = key
+                    .open_chunk(&aad(&chunk.id, header), body)
+                    .map_err(|_| BackupError::Tampered(path.clone()))?;
+                &opened[..]
+            }
+            (1, None) => return Err(BackupError::KeyRequired(path)),
+            // A plaintext chunk where an encrypted one belongs.
+            _ => return Err(BackupError::Tampered(path)),
+        };
+
+        // Read one byte past the recorded size so an overlong chunk shows up
+        // without decompressing an unbounded amount.
+        let mut data = Vec::with_capacity(chunk.size as usize);
+        codec::decoder(codec, payload)
+            .and_then(|decoder| decoder.take(chunk.size + 1).read_to_end(&mut data))
+            .map_err(|e| BackupError::io(&path, e))?;
+
+        let actual = self.id_of(&data);
+        if data.len() as u64 != chunk.size || actual != chunk.id {
+            return Err(BackupError::ChecksumMismatch {
+                path,
+                expected: chunk.id.clone(),
+                actual,
+            });
+        }
+        Ok(data)
+    }
+}
+
+fn header(codec: Codec, encrypted: bool) -> [u8; HEADER_SIZE] {
+    [MAGIC[0], MAGIC[1], MAGIC[2], MAGIC[3], codec.tag(), encrypted as u8]
+}
+
+/// Binds a sealed chunk to its id and header, so neither can be swapped.
+fn aad(id: &str, header: &[u8]) -> Vec<u8> {
+    [id.as_bytes(), header].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::generations::read_record;
+
+    fn scratch_repo() -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_chunks_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        Repository::init(&dir).unwrap()
+    }
+
+    fn chunk_files(repo: &Repository) -> usize {
+        let dir = repo.root().join("chunks");
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|fan| fs::read_dir(fan.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn chunks_read_back_with_every_codec() {
+        let repo = scratch_repo();
+        let text = b"a line that repeats\n".repeat(100);
+        let random: Vec<u8> = (0..4096u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+        for name in ["none", "zstd", "gzip", "lz4"] {
+            let store = ChunkStore::new(&repo, Compression::parse(name).unwrap(), None);
+            for data in [&text[..], &random[..], b""] {
+                let (chunk, _) = store.put(data).unwrap();
+                assert_eq!(chunk.size, data.len() as u64);
+                assert_eq!(store.get(&chunk).unwrap(), data, "{}", name);
+            }
+        }
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn same_content_is_stored_once() {
+        let repo = scratch_repo();
+        let store = ChunkStore::new(&repo, Compression::parse("zstd").unwrap(), None);
+        let data = b"the same bytes twice\n".repeat(50);
+
+        let (first, written) = store.put(&data).unwrap();
+        assert!(written > 0);
+        let (second, written) = store.put(&data).unwrap();
+        assert_eq!(second, first);
+        assert_eq!(written, 0);
+        assert_eq!(chunk_files(&repo), 1);
+
+        store.put(b"other bytes").unwrap();
+        assert_eq!(chunk_files(&repo), 2);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn oversized_chunk_is_refused() {
+        let repo = scratch_repo();
+        let store = ChunkStore::new(&repo, Compression::default(), None);
+        let (mut chunk, _) = store.put(b"small").unwrap();
+        chunk.size = u64::MAX;
+        assert!(matches!(store.get(&chunk), Err(BackupError::Io { .. })));
+
+        let record = repo.root().join("1.json");
+        let json = format!(
+            r#"{{"id":1,"size":0,"created":"2024-01-01T00:00:00Z","sha256":"","chunks":[{{"id":"{}","size":{}}}]}}"#,
+            chunk.id,
+            MAX_CHUNK_SIZE as u64 + 1
+        );
+        fs::write(&record, json).unwrap();
+        let error = read_record(repo.root(), 1, &record).unwrap_err();
+        assert!(error.to_string().contains("larger than"), "{}", error);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+}
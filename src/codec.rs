@@ -0,0 +1,158 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+/// Compression applied to a generation's stored data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Stored as a raw byte copy.
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+    Lz4,
+}
+
+impl Codec {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    /// One-byte tag stored in chunk headers.
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Gzip => 2,
+            Codec::Lz4 => 3,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Codec> {
+        match tag {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Gzip),
+            3 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+/// A codec plus its level, as configured by `compression = <codec>[:<level>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Compression {
+    pub codec: Codec,
+    /// `None` uses the codec's default (zstd 3, gzip 6). lz4 has no levels.
+    pub level: Option<i32>,
+}
+
+impl Compression {
+    /// Parses `none`, `zstd`, `zstd:19`, `gzip:9` or `lz4`.
+    pub fn parse(text: &str) -> Option<Compression> {
+        let (name, level) = match text.trim().split_once(':') {
+            Some((name, level)) => (name, Some(level.trim().parse::<i32>().ok()?)),
+            None => (text.trim(), None),
+        };
+
+        let codec = match name.to_ascii_lowercase().as_str() {
+            "none" | "off" => Codec::None,
+            "zstd" => Codec::Zstd,
+            "gzip" | "gz" => Codec::Gzip,
+            "lz4" => Codec::Lz4,
+            _ => return None,
+        };
+
+        let level_ok = match (codec, level) {
+            (_, None) => true,
+            (Codec::Zstd, Some(level)) => zstd::compression_level_range().contains(&level),
+            (Codec::Gzip, Some(level)) => (0..=9).contains(&level),
+            (Codec::None | Codec::Lz4, Some(_)) => false,
+        };
+        level_ok.then_some(Compression { codec, level })
+    }
+
+    /// Wraps `inner` so everything written to it is compressed.
+    pub(crate) fn encoder<'a, W: Write + 'a>(self, inner: W) -> io::Result<Box<dyn Encoder<W> + 'a>> {
+        Ok(match self.codec {
+            Codec::None => Box::new(Plain(inner)),
+            Codec::Zstd => Box::new(zstd::Encoder::new(inner, self.level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?),
+            Codec::Gzip => {
+                let level = self.level.map_or(flate2::Compression::default(), |l| flate2::Compression::new(l as u32));
+                Box::new(GzEncoder::new(inner, level))
+            }
+            Codec::Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(inner)),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "{}:{}", self.codec.as_str(), level),
+            None => write!(f, "{}", self.codec.as_str()),
+        }
+    }
+}
+
+/// Wraps `inner` so reading from it yields the original bytes.
+pub(crate) fn decoder<'a, R: Read + 'a>(codec: Codec, inner: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match codec {
+        Codec::None => Box::new(inner),
+        Codec::Zstd => Box::new(zstd::Decoder::new(inner)?),
+        Codec::Gzip => Box::new(GzDecoder::new(inner)),
+        Codec::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(inner)),
+    })
+}
+
+/// A compressing writer that must be finished to flush its trailer.
+pub(crate) trait Encoder<W>: Write {
+    /// Writes any buffered data and trailer and hands back the inner writer.
+    fn finish(self: Box<Self>) -> io::Result<W>;
+}
+
+struct Plain<W>(W);
+
+impl<W: Write> Write for Plain<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Encoder<W> for Plain<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<W> {
+        self.0.flush()?;
+        Ok(self.0)
+    }
+}
+
+impl<W: Write> Encoder<W> for zstd::Encoder<'_, W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        (*self).finish()
+    }
+}
+
+impl<W: Write> Encoder<W> for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        (*self).finish()
+    }
+}
+
+impl<W: Write> Encoder<W> for lz4_flex::frame::FrameEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<W> {
+        (*self).finish().map_err(io::Error::other)
+    }
+}
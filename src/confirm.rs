@@ -0,0 +1,60 @@
+use std::fmt;
+use std::path::Path;
+
+/// A question the engine needs answered before it destroys data.
+#[derive(Debug, Clone, Copy)]
+pub enum Prompt<'a> {
+    /// Restoring would replace an existing file or directory.
+    OverwriteTarget { target: &'a Path },
+    /// The file is about to be deleted.
+    Delete { target: &'a Path },
+}
+
+impl fmt::Display for Prompt<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prompt::OverwriteTarget { target } => {
+                let kind = if target.is_dir() { "directory" } else { "file" };
+                write!(f, "Target {} {} already exists. Overwrite?", kind, target.display())
+            }
+            Prompt::Delete { target } => {
+                write!(f, "Are you sure you want to delete {}?", target.display())
+            }
+        }
+    }
+}
+
+/// Confirmation policy consulted by [`backup`](crate::backup),
+/// [`restore`](crate::restore) and [`delete`](crate::delete).
+///
+/// Returning `false` cancels the operation without side effects. Closures of
+/// the form `FnMut(&Prompt) -> bool` implement this trait.
+pub trait Confirm {
+    fn confirm(&mut self, prompt: &Prompt<'_>) -> bool;
+}
+
+impl<F: FnMut(&Prompt<'_>) -> bool> Confirm for F {
+    fn confirm(&mut self, prompt: &Prompt<'_>) -> bool {
+        self(prompt)
+    }
+}
+
+/// Accepts every prompt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AssumeYes;
+
+impl Confirm for AssumeYes {
+    fn confirm(&mut self, _prompt: &Prompt<'_>) -> bool {
+        true
+    }
+}
+
+/// Declines every prompt, so nothing existing is ever replaced or deleted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AssumeNo;
+
+impl Confirm for AssumeNo {
+    fn confirm(&mut self, _prompt: &Prompt<'_>) -> bool {
+        false
+    }
+}
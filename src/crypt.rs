@@ -0,0 +1,479 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::digest::to_hex;
+use crate::error::{BackupError, Result};
+
+/// Plaintext bytes sealed per segment in single-file generations. Every
+/// segment is authenticated on its own, so memory stays bounded and nothing
+/// unauthenticated is ever handed on.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+pub(crate) const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 16;
+const KEY_CHECK_AAD: &[u8] = b"safe_backup key check";
+/// Largest Argon2id memory (KiB), passes and lanes accepted from a record,
+/// far above the defaults new generations use; a crafted record cannot make
+/// a restore allocate or compute without bound.
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+const CHUNK_ID_LABEL: &[u8] = b"safe_backup chunk id";
+
+/// Where the encryption key comes from.
+#[derive(Clone)]
+pub enum KeySource {
+    /// Stretched into a key with Argon2id and the repository's salt.
+    Passphrase(String),
+    /// A file holding 32 raw bytes or 64 hex digits.
+    KeyFile(PathBuf),
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Passphrase(_) => write!(f, "Passphrase(..)"),
+            KeySource::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+        }
+    }
+}
+
+/// How a generation was encrypted; stored in its record so the right key
+/// can be derived again on restore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Always `xchacha20poly1305` for now.
+    pub cipher: String,
+    pub kdf: Kdf,
+    /// Hex; the first bytes of every segment nonce. Only single-file
+    /// generations have one; chunks carry their own nonces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce_prefix: Option<String>,
+    /// Hex nonce and sealed empty message, used to tell a wrong key apart
+    /// from tampered data.
+    pub key_check_nonce: String,
+    pub key_check: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Kdf {
+    Argon2id { salt: String, m_cost: u32, t_cost: u32, p_cost: u32 },
+    KeyFile,
+}
+
+/// A ready-to-use cipher, plus the key that names encrypted chunks.
+pub(crate) struct SealingKey {
+    cipher: XChaCha20Poly1305,
+    id_key: [u8; KEY_SIZE],
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl SealingKey {
+    /// Derives the key for a new generation and describes it for the record.
+    ///
+    /// Passphrases are stretched with the repository-wide `salt`, so every
+    /// generation made with the same passphrase gets the same key and their
+    /// chunks deduplicate against each other.
+    pub(crate) fn create(source: &KeySource, salt: &str, path: &Path) -> Result<(SealingKey, Encryption)> {
+        let (key, kdf) = match source {
+            KeySource::Passphrase(passphrase) => {
+                let params = Params::default();
+                let kdf = Kdf::Argon2id {
+                    salt: salt.to_string(),
+                    m_cost: params.m_cost(),
+                    t_cost: params.t_cost(),
+                    p_cost: params.p_cost(),
+                };
+                (stretch(passphrase, &kdf, path)?, kdf)
+            }
+            KeySource::KeyFile(key_path) => (read_key_file(key_path, "key_file")?, Kdf::KeyFile),
+        };
+
+        let mut key_check_nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut key_check_nonce);
+
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        let key_check = cipher
+            .encrypt(XNonce::from_slice(&key_check_nonce), Payload { msg: b"", aad: KEY_CHECK_AAD })
+            .map_err(|_| crypto_failure(path, "sealing key check"))?;
+
+        let encryption = Encryption {
+            cipher: "xchacha20poly1305".to_string(),
+            kdf,
+            nonce_prefix: None,
+            key_check_nonce: to_hex(&key_check_nonce),
+            key_check: to_hex(&key_check),
+        };
+        let key = SealingKey {
+            cipher,
+            id_key: id_key(&key),
+            nonce_prefix: [0; NONCE_PREFIX_SIZE],
+        };
+        Ok((key, encryption))
+    }
+
+    /// Re-derives the key of an existing generation, failing with
+    /// [`BackupError::WrongKey`] if `source` does not produce it.
+    pub(crate) fn open(source: &KeySource, encryption: &Encryption, path: &Path) -> Result<SealingKey> {
+        let wrong_key = || BackupError::WrongKey(path.to_path_buf());
+        let key = match (&encryption.kdf, source) {
+            (Kdf::Argon2id { .. }, KeySource::Passphrase(passphrase)) => stretch(passphrase, &encryption.kdf, path)?,
+            (Kdf::KeyFile, KeySource::KeyFile(key_path)) => read_key_file(key_path, "key_file")?,
+            _ => return Err(wrong_key()),
+        };
+
+        let nonce_prefix = match &encryption.nonce_prefix {
+            Some(hex) => from_hex(hex)
+                .and_then(|bytes| <[u8; NONCE_PREFIX_SIZE]>::try_from(bytes).ok())
+                .ok_or_else(|| BackupError::Tampered(path.to_path_buf()))?,
+            None => [0; NONCE_PREFIX_SIZE],
+        };
+        let key_check_nonce = from_hex(&encryption.key_check_nonce)
+            .filter(|bytes| bytes.len() == 24)
+            .ok_or_else(|| BackupError::Tampered(path.to_path_buf()))?;
+        let key_check = from_hex(&encryption.key_check).ok_or_else(|| BackupError::Tampered(path.to_path_buf()))?;
+
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        cipher
+            .decrypt(
+                XNonce::from_slice(&key_check_nonce),
+                Payload { msg: &key_check, aad: KEY_CHECK_AAD },
+            )
+            .map_err(|_| wrong_key())?;
+
+        Ok(SealingKey {
+            cipher,
+            id_key: id_key(&key),
+            nonce_prefix,
+        })
+    }
+
+    /// Hex id of a chunk under this key: an HMAC rather than a bare hash, so
+    /// chunk names reveal nothing about their plaintext without the key.
+    pub(crate) fn chunk_id(&self, data: &[u8]) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.id_key).expect("HMAC takes any key length");
+        mac.update(data);
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    /// Seals one chunk under a fresh random nonce, returned in front of the
+    /// ciphertext.
+    pub(crate) fn seal_chunk(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let sealed = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let mut output = nonce.to_vec();
+        output.extend_from_slice(&sealed);
+        Ok(output)
+    }
+
+    /// Opens a chunk written by [`seal_chunk`](SealingKey::seal_chunk).
+    pub(crate) fn open_chunk(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let auth_failure = || io::Error::new(io::ErrorKind::InvalidData, AuthFailure);
+        if sealed.len() < 24 + TAG_SIZE {
+            return Err(auth_failure());
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| auth_failure())
+    }
+
+    /// Nonce for segment `index`: prefix, big-endian counter, last flag.
+    fn nonce(&self, index: u32, last: bool) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&index.to_be_bytes());
+        nonce[NONCE_PREFIX_SIZE + 4] = last as u8;
+        *XNonce::from_slice(&nonce)
+    }
+}
+
+/// A fresh random salt for [`Kdf::Argon2id`], hex-encoded.
+pub(crate) fn random_salt() -> String {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    to_hex(&salt)
+}
+
+/// Subkey for chunk ids, kept apart from the encryption key itself.
+fn id_key(key: &[u8; KEY_SIZE]) -> [u8; KEY_SIZE] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(CHUNK_ID_LABEL);
+    mac.finalize().into_bytes().into()
+}
+
+fn stretch(passphrase: &str, kdf: &Kdf, path: &Path) -> Result<[u8; KEY_SIZE]> {
+    let Kdf::Argon2id { salt, m_cost, t_cost, p_cost } = kdf else {
+        return Err(crypto_failure(path, "unexpected key derivation"));
+    };
+    let salt = from_hex(salt).ok_or_else(|| BackupError::Tampered(path.to_path_buf()))?;
+    if *m_cost > MAX_M_COST || *t_cost > MAX_T_COST || *p_cost > MAX_P_COST {
+        return Err(BackupError::Tampered(path.to_path_buf()));
+    }
+    let params = Params::new(*m_cost, *t_cost, *p_cost, Some(KEY_SIZE))
+        .map_err(|_| BackupError::Tampered(path.to_path_buf()))?;
+
+    let mut key = [0u8; KEY_SIZE];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+        .map_err(|_| crypto_failure(path, "key derivation"))?;
+    Ok(key)
+}
+
+/// Reads a key in the format `key_file` takes; `setting` names the setting
+/// in the error for a malformed one.
+pub(crate) fn read_key_file(path: &Path, setting: &str) -> Result<[u8; KEY_SIZE]> {
+    let bytes = fs::read(path).map_err(|e| BackupError::io(path, e))?;
+    let key = if bytes.len() == KEY_SIZE {
+        Some(bytes)
+    } else {
+        std::str::from_utf8(&bytes).ok().and_then(|text| from_hex(text.trim()))
+    };
+
+    key.and_then(|key| <[u8; KEY_SIZE]>::try_from(key).ok())
+        .ok_or_else(|| BackupError::InvalidSetting {
+            key: setting.to_string(),
+            value: format!("{} (need 32 raw bytes or 64 hex digits)", path.display()),
+        })
+}
+
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn crypto_failure(path: &Path, what: &str) -> BackupError {
+    BackupError::io(path, io::Error::other(format!("{} failed", what)))
+}
+
+/// Marker carried inside the `io::Error` a [`DecryptReader`] returns when a
+/// segment fails authentication.
+#[derive(Debug)]
+pub(crate) struct AuthFailure;
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ciphertext failed authentication")
+    }
+}
+
+impl Error for AuthFailure {}
+
+pub(crate) fn is_auth_failure(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<AuthFailure>())
+}
+
+/// Opens the segments of a single-file generation, releasing a segment's
+/// plaintext only after its tag has been checked.
+///
+/// Each segment holds [`SEGMENT_SIZE`] bytes of plaintext; the final one is
+/// always shorter (possibly empty) and carries the last flag, so truncation
+/// at a segment boundary is detected.
+pub(crate) struct DecryptReader<R> {
+    inner: R,
+    key: SealingKey,
+    plaintext: Vec<u8>,
+    position: usize,
+    index: u32,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub(crate) fn new(inner: R, key: SealingKey) -> Self {
+        DecryptReader {
+            inner,
+            key,
+            plaintext: Vec::new(),
+            position: 0,
+            index: 0,
+            done: false,
+        }
+    }
+
+    fn open_segment(&mut self) -> io::Result<()> {
+        let mut sealed = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+        let mut filled = 0;
+        while filled < sealed.len() {
+            match self.inner.read(&mut sealed[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        sealed.truncate(filled);
+
+        // Only the final segment is short; a full one is never last.
+        let last = filled < SEGMENT_SIZE + TAG_SIZE;
+        let nonce = self.key.nonce(self.index, last);
+        self.plaintext = self
+            .key
+            .cipher
+            .decrypt(&nonce, sealed.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, AuthFailure))?;
+        self.position = 0;
+        self.index = self.index.wrapping_add(1);
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.open_segment()?;
+        }
+
+        let n = buf.len().min(self.plaintext.len() - self.position);
+        buf[..n].copy_from_slice(&self.plaintext[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::chunks::{ChunkRef, ChunkStore};
+    use crate::codec::Compression;
+    use crate::repository::Repository;
+
+    /// A new repository in a fresh directory, with two key files beside
+    /// its contents.
+    fn scratch_repo() -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_crypt_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        fs::write(dir.join("a.key"), [1u8; KEY_SIZE]).unwrap();
+        fs::write(dir.join("b.key"), "02".repeat(KEY_SIZE)).unwrap();
+        repo
+    }
+
+    fn key_file(repo: &Repository, name: &str) -> KeySource {
+        KeySource::KeyFile(repo.root().join(name))
+    }
+
+    fn sealing_key(repo: &Repository) -> (SealingKey, Encryption) {
+        SealingKey::create(&key_file(repo, "a.key"), "00", repo.root()).unwrap()
+    }
+
+    /// Stores one encrypted chunk and returns its reference and path.
+    fn sealed_chunk(repo: &Repository) -> (ChunkRef, PathBuf) {
+        let (key, _) = sealing_key(repo);
+        let store = ChunkStore::new(repo, Compression::default(), Some(key));
+        let (chunk, _) = store.put(b"the quick brown fox jumps over the lazy dog").unwrap();
+        let path = repo.chunk_path(&chunk.id);
+        (chunk, path)
+    }
+
+    #[test]
+    fn right_key_opens_and_wrong_key_is_refused() {
+        let repo = scratch_repo();
+        let (_, encryption) = sealing_key(&repo);
+
+        assert!(SealingKey::open(&key_file(&repo, "a.key"), &encryption, repo.root()).is_ok());
+        let wrong = SealingKey::open(&key_file(&repo, "b.key"), &encryption, repo.root());
+        assert!(matches!(wrong, Err(BackupError::WrongKey(_))));
+        let passphrase = SealingKey::open(&KeySource::Passphrase("x".to_string()), &encryption, repo.root());
+        assert!(matches!(passphrase, Err(BackupError::WrongKey(_))));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn sealed_chunk_reads_back_with_its_key() {
+        let repo = scratch_repo();
+        let (chunk, path) = sealed_chunk(&repo);
+        let stored = fs::read(&path).unwrap();
+        assert!(!stored.windows(5).any(|w| w == b"quick"));
+
+        let (key, _) = sealing_key(&repo);
+        let store = ChunkStore::new(&repo, Compression::default(), Some(key));
+        assert_eq!(store.get(&chunk).unwrap(), b"the quick brown fox jumps over the lazy dog");
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn flipped_ciphertext_byte_is_tampering() {
+        let repo = scratch_repo();
+        let (chunk, path) = sealed_chunk(&repo);
+        let mut stored = fs::read(&path).unwrap();
+        let last = stored.len() - 1;
+        stored[last] ^= 1;
+        fs::write(&path, stored).unwrap();
+
+        let (key, _) = sealing_key(&repo);
+        let store = ChunkStore::new(&repo, Compression::default(), Some(key));
+        assert!(matches!(store.get(&chunk), Err(BackupError::Tampered(_))));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn truncated_chunk_is_tampering() {
+        let repo = scratch_repo();
+        let (chunk, path) = sealed_chunk(&repo);
+        let stored = fs::read(&path).unwrap();
+        fs::write(&path, &stored[..stored.len() / 2]).unwrap();
+
+        let (key, _) = sealing_key(&repo);
+        let store = ChunkStore::new(&repo, Compression::default(), Some(key));
+        assert!(matches!(store.get(&chunk), Err(BackupError::Tampered(_))));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn encrypted_chunk_needs_a_key() {
+        let repo = scratch_repo();
+        let (chunk, _) = sealed_chunk(&repo);
+
+        let store = ChunkStore::new(&repo, Compression::default(), None);
+        assert!(matches!(store.get(&chunk), Err(BackupError::KeyRequired(_))));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn oversized_argon2_parameters_are_refused_before_hashing() {
+        let kdf = |m_cost, t_cost, p_cost| Kdf::Argon2id {
+            salt: "00".repeat(SALT_SIZE),
+            m_cost,
+            t_cost,
+            p_cost,
+        };
+        let path = Path::new("record.json");
+        for kdf in [kdf(u32::MAX, 2, 1), kdf(19 * 1024, u32::MAX, 1), kdf(19 * 1024, 2, 1 << 20)] {
+            assert!(matches!(stretch("secret", &kdf, path), Err(BackupError::Tampered(_))));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::generations::Selector;
+
+/// Every way a backup, restore or delete can fail.
+#[derive(Debug)]
+pub enum BackupError {
+    /// The name failed [`validate_filename`](crate::validate_filename).
+    InvalidName(String),
+    /// The source file or backup does not exist.
+    NotFound(PathBuf),
+    /// No backup generation of `name` matches the selector.
+    NoGeneration { name: String, selector: Selector },
+    /// The trash holds no deleted file of this name.
+    NotInTrash(String),
+    /// There is no initialized repository at this path.
+    NoRepository(PathBuf),
+    /// `init` found a repository already in place.
+    RepositoryExists(PathBuf),
+    /// The repository was written in a format this build cannot read.
+    UnsupportedFormat { path: PathBuf, version: String },
+    /// A repository setting or command-line override has an unusable value.
+    InvalidSetting { key: String, value: String },
+    /// The file exceeds the size limit.
+    TooLarge { path: PathBuf, size: u64, limit: u64 },
+    /// Fewer bytes reached the destination than the source holds.
+    ShortCopy { path: PathBuf, expected: u64, copied: u64 },
+    /// Stored data no longer matches the digest recorded at backup time.
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+    /// The generation is encrypted but no key was supplied.
+    KeyRequired(PathBuf),
+    /// The supplied key or passphrase is not the one the generation was
+    /// encrypted with.
+    WrongKey(PathBuf),
+    /// Encrypted data failed authentication: it was altered or truncated.
+    Tampered(PathBuf),
+    /// [`verify`](crate::verify) found damaged or missing generations.
+    Damaged { problems: usize },
+    /// The [`Confirm`](crate::Confirm) policy declined the operation.
+    Cancelled,
+    /// Another run holds a lock on `path` and did not release it in time.
+    /// `pid` is that run's process, when it could be told.
+    Locked { path: PathBuf, pid: Option<u32> },
+    /// [`verify_log`](crate::verify_log) found the audit log's chain broken.
+    LogTampered { problems: usize },
+    /// The operation finished but its audit entry could not be written.
+    Log(io::Error),
+    /// Any other I/O failure, with the path it happened on.
+    Io { path: PathBuf, source: io::Error },
+}
+
+pub type Result<T> = std::result::Result<T, BackupError>;
+
+impl BackupError {
+    pub(crate) fn io(path: &Path, source: io::Error) -> Self {
+        BackupError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Process exit code used by the command-line front-end.
+    ///
+    /// `0` is success and `2` is reserved for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BackupError::InvalidName(_) => 3,
+            BackupError::NotFound(_) | BackupError::NoGeneration { .. } | BackupError::NotInTrash(_) => 4,
+            BackupError::TooLarge { .. } => 5,
+            BackupError::ShortCopy { .. } => 6,
+            BackupError::Cancelled => 7,
+            BackupError::Log(_) => 8,
+            BackupError::Io { .. } => 9,
+            BackupError::NoRepository(_) | BackupError::RepositoryExists(_) => 10,
+            BackupError::UnsupportedFormat { .. } => 11,
+            BackupError::ChecksumMismatch { .. } => 12,
+            BackupError::Damaged { .. } => 13,
+            BackupError::InvalidSetting { .. } => 14,
+            BackupError::KeyRequired(_) => 15,
+            BackupError::WrongKey(_) => 16,
+            BackupError::Tampered(_) => 17,
+            BackupError::Locked { .. } => 18,
+            BackupError::LogTampered { .. } => 19,
+        }
+    }
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::InvalidName(name) => write!(f, "Invalid filename '{}'", name),
+            BackupError::NotFound(path) => write!(f, "'{}' not found", path.display()),
+            BackupError::NoGeneration { name, selector } => {
+                write!(f, "No backup of '{}' matches {}", name, selector)
+            }
+            BackupError::NotInTrash(name) => write!(f, "No deleted file named '{}' in the trash", name),
+            BackupError::NoRepository(path) => write!(
+                f,
+                "No backup repository at '{}' (run `init` first)",
+                path.display()
+            ),
+            BackupError::RepositoryExists(path) => {
+                write!(f, "A backup repository already exists at '{}'", path.display())
+            }
+            BackupError::UnsupportedFormat { path, version } => write!(
+                f,
+                "Repository '{}' has unsupported format version '{}'",
+                path.display(),
+                version
+            ),
+            BackupError::InvalidSetting { key, value } => {
+                write!(f, "Invalid value '{}' for setting '{}'", value, key)
+            }
+            BackupError::TooLarge { path, size, limit } => write!(
+                f,
+                "'{}' is too large ({} bytes, limit {} bytes)",
+                path.display(),
+                size,
+                limit
+            ),
+            BackupError::ShortCopy { path, expected, copied } => write!(
+                f,
+                "Failed to copy entire file to '{}' ({} of {} bytes)",
+                path.display(),
+                copied,
+                expected
+            ),
+            BackupError::ChecksumMismatch { path, expected, actual } => write!(
+                f,
+                "Checksum mismatch for '{}': recorded sha256 {}, found {}",
+                path.display(),
+                expected,
+                actual
+            ),
+            BackupError::KeyRequired(path) => write!(
+                f,
+                "'{}' is encrypted; supply a passphrase or key file",
+                path.display()
+            ),
+            BackupError::WrongKey(path) => write!(f, "Wrong key or passphrase for '{}'", path.display()),
+            BackupError::Tampered(path) => write!(
+                f,
+                "Encrypted data in '{}' failed authentication (tampered or truncated)",
+                path.display()
+            ),
+            BackupError::Damaged { problems } => {
+                write!(f, "Verification found {} damaged or missing backup(s)", problems)
+            }
+            BackupError::Cancelled => write!(f, "Cancelled by user"),
+            BackupError::Locked { path, pid: Some(pid) } => {
+                write!(f, "'{}' is locked by pid {}", path.display(), pid)
+            }
+            BackupError::Locked { path, pid: None } => {
+                write!(f, "'{}' is locked by another process", path.display())
+            }
+            BackupError::LogTampered { problems } => {
+                write!(f, "The audit log failed verification with {} problem(s)", problems)
+            }
+            BackupError::Log(e) => write!(f, "Could not write audit log: {}", e),
+            BackupError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl Error for BackupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BackupError::Log(e) | BackupError::Io { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::{BackupError, Result};
+
+/// Mode for backup data, records and every temporary file: owner read/write.
+pub(crate) const PRIVATE_FILE_MODE: u32 = 0o600;
+/// Mode for repository directories and temporary directories.
+pub(crate) const PRIVATE_DIR_MODE: u32 = 0o700;
+
+/// Suffix of every temp file, and of the copy a restore moves aside.
+const TEMP_SUFFIX: &str = ".tmp";
+const OLD_SUFFIX: &str = ".old.tmp";
+
+/// How hard a write works to survive a crash or power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Leave flushing to the operating system. Fastest; a crash can lose
+    /// recent writes or leave an empty file behind.
+    None,
+    /// Flush a file's data to disk before it is renamed into place, so a
+    /// file that appears is complete. The rename itself may still be lost.
+    Data,
+    /// Flush the file and its metadata, then the directory holding it after
+    /// the rename, so a write that returned is on disk.
+    #[default]
+    Full,
+}
+
+impl Durability {
+    /// Parses `none`, `data` or `full`.
+    pub fn parse(text: &str) -> Option<Durability> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "none" | "off" => Some(Durability::None),
+            "data" => Some(Durability::Data),
+            "full" => Some(Durability::Full),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Durability::None => "none",
+            Durability::Data => "data",
+            Durability::Full => "full",
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The calls that make a write durable. Everything goes through the real
+/// filesystem; the tests substitute a layer that records and fails them.
+pub(crate) trait Filesystem {
+    fn sync_file(&self, file: &fs::File, path: &Path, data_only: bool) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+pub(crate) struct RealFilesystem;
+
+impl Filesystem for RealFilesystem {
+    fn sync_file(&self, file: &fs::File, _path: &Path, data_only: bool) -> io::Result<()> {
+        if data_only { file.sync_data() } else { file.sync_all() }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::File::open(dir)?.sync_all()
+    }
+}
+
+/// A tag unique to this process and call, `<pid>-<n>`. Temp files carry it so
+/// concurrent writers never share one and a leftover names the process that
+/// abandoned it.
+pub(crate) fn unique_tag() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("{}-{}", process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A file name recognised as a temp file: `<base>.<pid>-<n>.tmp`, or
+/// `<base>.<pid>-<n>.old.tmp` for a copy moved aside by a restore. Names
+/// written before temp files were tagged (`<base>.tmp`) have no `pid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TempName<'a> {
+    pub(crate) base: &'a str,
+    pub(crate) pid: Option<u32>,
+    pub(crate) old: bool,
+}
+
+impl<'a> TempName<'a> {
+    pub(crate) fn parse(name: &'a str) -> Option<TempName<'a>> {
+        let (rest, old) = match name.strip_suffix(OLD_SUFFIX) {
+            Some(rest) => (rest, true),
+            None => (name.strip_suffix(TEMP_SUFFIX)?, false),
+        };
+        let tagged = rest.rsplit_once('.').and_then(|(base, tag)| {
+            let (pid, n) = tag.split_once('-')?;
+            n.bytes().all(|b| b.is_ascii_digit()).then_some(())?;
+            Some((base, pid.parse().ok()?))
+        });
+        let (base, pid) = match tagged {
+            Some((base, pid)) => (base, Some(pid)),
+            None => (rest, None),
+        };
+        (!base.is_empty()).then_some(TempName { base, pid, old })
+    }
+}
+
+/// Creates `path` exclusively with `mode`. The permissions are set by the
+/// open call itself, so the file is never visible with looser ones, and an
+/// existing file or symlink at `path` is refused rather than followed.
+pub(crate) fn create_new(path: &Path, mode: u32) -> Result<fs::File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .map_err(|e| BackupError::io(path, e))
+}
+
+/// Flushes a file written at `path` as far as `durability` asks.
+pub(crate) fn sync_file(file: &fs::File, path: &Path, durability: Durability) -> Result<()> {
+    sync_file_with(&RealFilesystem, file, path, durability)
+}
+
+fn sync_file_with(fs: &dyn Filesystem, file: &fs::File, path: &Path, durability: Durability) -> Result<()> {
+    match durability {
+        Durability::None => Ok(()),
+        Durability::Data => fs.sync_file(file, path, true),
+        Durability::Full => fs.sync_file(file, path, false),
+    }
+    .map_err(|e| BackupError::io(path, e))
+}
+
+/// Flushes the directory holding `path`, making a rename or create in it
+/// durable; only at [`Durability::Full`].
+fn sync_parent(fs: &dyn Filesystem, path: &Path, durability: Durability) -> Result<()> {
+    if durability != Durability::Full {
+        return Ok(());
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs.sync_dir(parent).map_err(|e| BackupError::io(parent, e))
+}
+
+/// Flushes the directory holding `path` after a rename into or out of it,
+/// as far as `durability` asks.
+pub(crate) fn sync_renamed(path: &Path, durability: Durability) -> Result<()> {
+    sync_parent(&RealFilesystem, path, durability)
+}
+
+/// Creates `dir` and any missing parents with `mode`. At
+/// [`Durability::Full`] the entry of each directory created is flushed into
+/// its parent, so files renamed into it later cannot vanish with it.
+pub(crate) fn create_dir_all(dir: &Path, mode: u32, durability: Durability) -> Result<()> {
+    create_dir_all_with(&RealFilesystem, dir, mode, durability)
+}
+
+fn create_dir_all_with(fs: &dyn Filesystem, dir: &Path, mode: u32, durability: Durability) -> Result<()> {
+    let missing: Vec<&Path> = dir.ancestors().take_while(|d| !d.as_os_str().is_empty() && !d.exists()).collect();
+    DirBuilder::new()
+        .recursive(true)
+        .mode(mode)
+        .create(dir)
+        .map_err(|e| BackupError::io(dir, e))?;
+
+    // Outermost first, so each parent is already durable itself.
+    for created in missing.into_iter().rev() {
+        sync_parent(fs, created, durability)?;
+    }
+    Ok(())
+}
+
+/// Creates a uniquely named temp file next to `to` with `mode`, lets `fill`
+/// write it, flushes it as far as `durability` asks, then renames it over
+/// `to`. If anything before the rename fails the temp file is removed and
+/// `to` is left as it was.
+pub(crate) fn write_atomically<T>(
+    to: &Path,
+    mode: u32,
+    durability: Durability,
+    fill: impl FnOnce(&mut fs::File, &Path) -> Result<T>,
+) -> Result<T> {
+    write_atomically_with(&RealFilesystem, to, mode, durability, fill)
+}
+
+fn write_atomically_with<T>(
+    fs: &dyn Filesystem,
+    to: &Path,
+    mode: u32,
+    durability: Durability,
+    fill: impl FnOnce(&mut fs::File, &Path) -> Result<T>,
+) -> Result<T> {
+    let tmp_path = with_suffix(to, &format!(".{}{}", unique_tag(), TEMP_SUFFIX));
+    let mut output_file = create_new(&tmp_path, mode)?;
+
+    let filled = fill(&mut output_file, &tmp_path)
+        .and_then(|value| sync_file_with(fs, &output_file, &tmp_path, durability).map(|()| value));
+    drop(output_file);
+    let value = match filled {
+        Ok(value) => value,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = fs.rename(&tmp_path, to) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(BackupError::io(to, e));
+    }
+    // The new data is in place; failing here only means it might not
+    // survive a crash, which is still worth reporting.
+    sync_parent(fs, to, durability)?;
+    Ok(value)
+}
+
+/// Lets `fill` build a new directory at a temp path next to `to`, created
+/// owner-only, then puts it in place of `to`. Whatever was at `to` is moved
+/// aside to a `.old.tmp` path until the swap has succeeded, and moved back if
+/// it fails.
+/// If `fill` fails the temp directory is removed and `to` is left as it was.
+///
+/// `fill` flushes the files it writes with [`sync_file`]; at
+/// [`Durability::Full`] every directory of the new tree is flushed before the
+/// swap and the parent of `to` after it.
+pub(crate) fn replace_dir<T>(to: &Path, durability: Durability, fill: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let fs = &RealFilesystem;
+    let tag = unique_tag();
+    let tmp_path = with_suffix(to, &format!(".{}{}", tag, TEMP_SUFFIX));
+    DirBuilder::new()
+        .mode(PRIVATE_DIR_MODE)
+        .create(&tmp_path)
+        .map_err(|e| BackupError::io(&tmp_path, e))?;
+
+    let filled = fill(&tmp_path).and_then(|value| {
+        if durability == Durability::Full {
+            sync_dirs(fs, &tmp_path)?;
+        }
+        Ok(value)
+    });
+    let value = match filled {
+        Ok(value) => value,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&tmp_path);
+            return Err(e);
+        }
+    };
+
+    let old_path = with_suffix(to, &format!(".{}{}", tag, OLD_SUFFIX));
+    let displaced = match fs::symlink_metadata(to) {
+        Ok(metadata) => {
+            fs.rename(to, &old_path).map_err(|e| BackupError::io(to, e))?;
+            Some(metadata)
+        }
+        Err(_) => None,
+    };
+    if let Err(e) = fs.rename(&tmp_path, to) {
+        if displaced.is_some() {
+            let _ = fs.rename(&old_path, to);
+        }
+        let _ = fs::remove_dir_all(&tmp_path);
+        return Err(BackupError::io(to, e));
+    }
+    sync_parent(fs, to, durability)?;
+
+    match displaced {
+        Some(metadata) if metadata.is_dir() => fs::remove_dir_all(&old_path),
+        Some(_) => fs::remove_file(&old_path),
+        None => Ok(()),
+    }
+    .map_err(|e| BackupError::io(&old_path, e))?;
+    Ok(value)
+}
+
+/// Flushes `dir` and every directory below it, deepest first. A directory
+/// restored without read permission cannot be opened and is left to the
+/// operating system.
+fn sync_dirs(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+        entries => entries.map_err(|e| BackupError::io(dir, e))?,
+    };
+    for entry in entries {
+        let entry = entry.map_err(|e| BackupError::io(dir, e))?;
+        if entry.file_type().map_err(|e| BackupError::io(&entry.path(), e))?.is_dir() {
+            sync_dirs(fs, &entry.path())?;
+        }
+    }
+    fs.sync_dir(dir).map_err(|e| BackupError::io(dir, e))
+}
+
+/// `path` with `suffix` appended to its last component.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::recover::{recover, RecoveryScope};
+    use crate::repository::Repository;
+
+    /// One durability-relevant call, as seen by [`FaultyFilesystem`].
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Op {
+        SyncData(PathBuf),
+        SyncAll(PathBuf),
+        Rename(PathBuf, PathBuf),
+        SyncDir(PathBuf),
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Kind {
+        SyncFile,
+        Rename,
+        SyncDir,
+    }
+
+    /// Passes every call to the real filesystem and records it, except calls
+    /// of the chosen kind, which fail instead.
+    #[derive(Default)]
+    struct FaultyFilesystem {
+        journal: RefCell<Vec<Op>>,
+        fail: Option<Kind>,
+    }
+
+    impl FaultyFilesystem {
+        fn failing(kind: Kind) -> Self {
+            FaultyFilesystem {
+                fail: Some(kind),
+                ..Default::default()
+            }
+        }
+
+        fn inject(&self, kind: Kind) -> io::Result<()> {
+            if self.fail == Some(kind) {
+                return Err(io::Error::other(format!("injected {:?} failure", kind)));
+            }
+            Ok(())
+        }
+
+        fn journal(&self) -> Vec<Op> {
+            self.journal.borrow().clone()
+        }
+
+        /// Whether `path` would hold its new contents after a power loss
+        /// now: its data was flushed before it was renamed into place, and
+        /// its directory was flushed after the rename.
+        fn survives_power_loss(&self, path: &Path) -> bool {
+            let journal = self.journal.borrow();
+            let Some(renamed) = journal.iter().position(|op| matches!(op, Op::Rename(_, to) if to == path)) else {
+                return false;
+            };
+            let Op::Rename(from, _) = &journal[renamed] else {
+                unreachable!()
+            };
+            let flushed = journal[..renamed]
+                .iter()
+                .any(|op| matches!(op, Op::SyncData(p) | Op::SyncAll(p) if p == from));
+            let parent = path.parent().unwrap();
+            let linked = journal[renamed..].iter().any(|op| matches!(op, Op::SyncDir(d) if d == parent));
+            flushed && linked
+        }
+    }
+
+    impl Filesystem for FaultyFilesystem {
+        fn sync_file(&self, file: &fs::File, path: &Path, data_only: bool) -> io::Result<()> {
+            self.inject(Kind::SyncFile)?;
+            let op = if data_only { Op::SyncData } else { Op::SyncAll };
+            self.journal.borrow_mut().push(op(path.to_path_buf()));
+            RealFilesystem.sync_file(file, path, data_only)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inject(Kind::Rename)?;
+            self.journal
+                .borrow_mut()
+                .push(Op::Rename(from.to_path_buf(), to.to_path_buf()));
+            RealFilesystem.rename(from, to)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            self.inject(Kind::SyncDir)?;
+            self.journal.borrow_mut().push(Op::SyncDir(dir.to_path_buf()));
+            RealFilesystem.sync_dir(dir)
+        }
+    }
+
+    /// A fresh empty directory under the system temp directory.
+    fn scratch_dir() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_files_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Names of the temp files left in `dir`.
+    fn leftovers(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| TempName::parse(name).is_some())
+            .collect()
+    }
+
+    /// The temp file `to` was renamed from.
+    fn renamed_from(fs: &FaultyFilesystem, to: &Path) -> PathBuf {
+        fs.journal()
+            .into_iter()
+            .find_map(|op| match op {
+                Op::Rename(from, dest) if dest == to => Some(from),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn write_new(fs: &FaultyFilesystem, to: &Path, durability: Durability) -> Result<()> {
+        write_atomically_with(fs, to, PRIVATE_FILE_MODE, durability, |file, tmp_path| {
+            file.write_all(b"new").map_err(|e| BackupError::io(tmp_path, e))
+        })
+    }
+
+    #[test]
+    fn full_flushes_file_then_renames_then_flushes_directory() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        let fs = FaultyFilesystem::default();
+
+        write_new(&fs, &to, Durability::Full).unwrap();
+
+        let tmp = renamed_from(&fs, &to);
+        assert_eq!(
+            fs.journal(),
+            vec![Op::SyncAll(tmp.clone()), Op::Rename(tmp, to.clone()), Op::SyncDir(dir.clone())]
+        );
+        assert!(fs.survives_power_loss(&to));
+        assert_eq!(fs::read(&to).unwrap(), b"new");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn data_flushes_only_the_file() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        let fs = FaultyFilesystem::default();
+
+        write_new(&fs, &to, Durability::Data).unwrap();
+
+        let tmp = renamed_from(&fs, &to);
+        assert_eq!(fs.journal(), vec![Op::SyncData(tmp.clone()), Op::Rename(tmp, to.clone())]);
+        assert!(!fs.survives_power_loss(&to));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn none_only_renames() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        let fs = FaultyFilesystem::default();
+
+        write_new(&fs, &to, Durability::None).unwrap();
+
+        assert_eq!(fs.journal(), vec![Op::Rename(renamed_from(&fs, &to), to.clone())]);
+        assert!(!fs.survives_power_loss(&to));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_file_sync_keeps_old_target_and_removes_temp() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        fs::write(&to, b"old").unwrap();
+        let fs = FaultyFilesystem::failing(Kind::SyncFile);
+
+        assert!(write_new(&fs, &to, Durability::Full).is_err());
+
+        assert_eq!(fs::read(&to).unwrap(), b"old");
+        assert!(leftovers(&dir).is_empty());
+        assert!(fs.journal().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_rename_keeps_old_target_and_removes_temp() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        fs::write(&to, b"old").unwrap();
+        let fs = FaultyFilesystem::failing(Kind::Rename);
+
+        assert!(write_new(&fs, &to, Durability::Full).is_err());
+
+        assert_eq!(fs::read(&to).unwrap(), b"old");
+        assert!(leftovers(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_directory_sync_is_reported_after_the_rename() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        let fs = FaultyFilesystem::failing(Kind::SyncDir);
+
+        assert!(write_new(&fs, &to, Durability::Full).is_err());
+
+        assert_eq!(fs::read(&to).unwrap(), b"new");
+        assert!(!fs.survives_power_loss(&to));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn temp_names_are_unique_and_name_their_process() {
+        let to = Path::new("dir/1.json");
+        let first = with_suffix(to, &format!(".{}{}", unique_tag(), TEMP_SUFFIX));
+        let second = with_suffix(to, &format!(".{}{}", unique_tag(), OLD_SUFFIX));
+        assert_ne!(first, second);
+
+        let first = first.file_name().unwrap().to_str().unwrap();
+        let parsed = TempName::parse(first).unwrap();
+        assert_eq!(parsed, TempName { base: "1.json", pid: Some(process::id()), old: false });
+        let second = second.file_name().unwrap().to_str().unwrap();
+        assert!(TempName::parse(second).unwrap().old);
+
+        assert_eq!(TempName::parse("1.bak.tmp"), Some(TempName { base: "1.bak", pid: None, old: false }));
+        assert_eq!(TempName::parse("1.json"), None);
+        assert_eq!(TempName::parse(".tmp"), None);
+    }
+
+    #[test]
+    fn recover_keeps_generations_of_a_name_that_looks_like_a_temp_file() {
+        let dir = scratch_dir();
+        let mut repo = Repository::init(dir.join("repo")).unwrap();
+        repo.settings_mut().log_path = Some(PathBuf::from("audit.log"));
+        let generations = repo.generations_dir("notes.tmp");
+        fs::create_dir_all(&generations).unwrap();
+        fs::write(generations.join("1.json"), b"{}").unwrap();
+        // Tagged with a pid no process can have.
+        let abandoned = generations.join("2.json.999999999-0.tmp");
+        fs::write(&abandoned, b"").unwrap();
+
+        let report = recover(&repo, RecoveryScope::Startup).unwrap();
+
+        assert!(generations.join("1.json").exists());
+        assert!(!abandoned.exists());
+        let removed: Vec<&Path> = report.entries.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(removed, vec![abandoned.as_path()]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_fill_leaves_nothing_behind() {
+        let dir = scratch_dir();
+        let to = dir.join("target");
+        let fs = FaultyFilesystem::default();
+
+        let result: Result<()> = write_atomically_with(&fs, &to, PRIVATE_FILE_MODE, Durability::Full, |_, tmp_path| {
+            Err(BackupError::io(tmp_path, io::Error::from(io::ErrorKind::StorageFull)))
+        });
+
+        assert!(result.is_err());
+        assert!(!to.exists());
+        assert!(leftovers(&dir).is_empty());
+        assert!(fs.journal().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn new_directories_are_flushed_into_their_parents() {
+        let dir = scratch_dir();
+        let nested = dir.join("a").join("b");
+        let fs = FaultyFilesystem::default();
+
+        create_dir_all_with(&fs, &nested, PRIVATE_DIR_MODE, Durability::Full).unwrap();
+
+        assert_eq!(fs.journal(), vec![Op::SyncDir(dir.clone()), Op::SyncDir(dir.join("a"))]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
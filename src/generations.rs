@@ -0,0 +1,243 @@
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::{is_chunk_id, ChunkRef, MAX_CHUNK_SIZE};
+use crate::codec::Codec;
+use crate::crypt::Encryption;
+use crate::error::{BackupError, Result};
+use crate::files::{create_dir_all, write_atomically};
+use crate::meta::FileMetadata;
+use crate::repository::Repository;
+use crate::tree::{entry_path, TreeEntry};
+
+/// One stored backup of a file or directory, described by the record `<id>.json` in the
+/// file's directory of the [`Repository`]. The data is the list of
+/// [`chunks`](Generation::chunks) in the repository's chunk store, or for a
+/// directory the [`tree`](Generation::tree) of its entries; records
+/// written before the chunk store existed point at a whole copy in `<id>.bak`
+/// next to them instead.
+///
+/// The record is written after the data, so a generation without one never
+/// finished and is not listed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// Sequence number, starting at 1 and increasing with every backup.
+    pub id: u64,
+    /// The `.bak` copy of an old single-file generation, else the record
+    /// itself; derived, not stored in the record.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Size of the original file.
+    pub size: u64,
+    pub created: DateTime<Local>,
+    /// Hex SHA-256 of the original data, taken while it was copied in.
+    pub sha256: String,
+    /// Compression the data is stored with.
+    #[serde(default)]
+    pub codec: Codec,
+    /// Bytes this generation added to the repository after compression:
+    /// only its new chunks, as chunks it shares with earlier backups cost
+    /// nothing. Absent in records written before compression existed, where
+    /// it equals `size`.
+    #[serde(default)]
+    pub stored_size: Option<u64>,
+    /// Present when the stored data is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// The chunks that make up a file, in order; `None` for a directory and
+    /// for a single-file generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<ChunkRef>>,
+    /// Mode, ownership, times and extended attributes of the file or
+    /// directory itself, unless the backup was made without them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    /// Every file and subdirectory of a directory snapshot, parents first.
+    /// `size` is then the total of its files and `sha256` its tree digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<TreeEntry>>,
+}
+
+impl Generation {
+    /// Whether this generation is a directory snapshot.
+    pub fn is_directory(&self) -> bool {
+        self.tree.is_some()
+    }
+
+    /// Every chunk the generation refers to, including those of the files
+    /// in a directory snapshot.
+    pub(crate) fn all_chunks(&self) -> impl Iterator<Item = &ChunkRef> {
+        let tree_chunks = self.tree.iter().flatten().flat_map(|entry| &entry.chunks);
+        self.chunks.iter().flatten().chain(tree_chunks)
+    }
+}
+
+/// Which generation [`restore`](crate::restore) brings back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selector {
+    /// The most recent generation.
+    Latest,
+    /// The generation with this id.
+    Version(u64),
+    /// The most recent generation created at or before this time.
+    At(DateTime<Local>),
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Latest => write!(f, "latest"),
+            Selector::Version(id) => write!(f, "version {}", id),
+            Selector::At(time) => write!(f, "at {}", time.format("%Y-%m-%d %H:%M:%S")),
+        }
+    }
+}
+
+/// Returns every generation of `filename` in `repo`, oldest first.
+pub fn list_generations(repo: &Repository, filename: &str) -> Result<Vec<Generation>> {
+    crate::validate_filename(filename)?;
+
+    let dir = repo.generations_dir(filename);
+    record_paths(&dir)?
+        .into_iter()
+        .map(|(id, record_path)| read_record(&dir, id, &record_path))
+        .collect()
+}
+
+/// Ids and record paths of every generation in `dir`, sorted by id. A
+/// missing `dir` simply has no generations.
+pub(crate) fn record_paths(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries = fs::read_dir(dir).map_err(|e| BackupError::io(dir, e))?;
+
+    let mut records = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| BackupError::io(dir, e))?;
+        if let Some(id) = parse_id(&entry.file_name().to_string_lossy()) {
+            records.push((id, entry.path()));
+        }
+    }
+
+    records.sort_by_key(|(id, _)| *id);
+    Ok(records)
+}
+
+pub(crate) fn read_record(dir: &Path, id: u64, record_path: &Path) -> Result<Generation> {
+    let text = fs::read_to_string(record_path).map_err(|e| BackupError::io(record_path, e))?;
+    let invalid = |e: String| BackupError::io(record_path, std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    let mut generation: Generation = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    if let Some(chunk) = generation.all_chunks().find(|c| !is_chunk_id(&c.id)) {
+        return Err(invalid(format!("invalid chunk id {:?}", chunk.id)));
+    }
+    if let Some(chunk) = generation.all_chunks().find(|c| c.size > MAX_CHUNK_SIZE as u64) {
+        return Err(invalid(format!("chunk {} larger than {} bytes", chunk.id, MAX_CHUNK_SIZE)));
+    }
+    if let Some(entry) = generation.tree.iter().flatten().find(|e| entry_path(&e.path).is_err()) {
+        return Err(invalid(format!("unsafe entry path {:?}", entry.path)));
+    }
+
+    generation.id = id;
+    generation.path = if generation.chunks.is_some() || generation.tree.is_some() {
+        record_path.to_path_buf()
+    } else {
+        dir.join(format!("{}.bak", id))
+    };
+    Ok(generation)
+}
+
+/// Writes the record that makes `generation` visible, via a temp file and
+/// rename so a half-written record is never read.
+pub(crate) fn write_record(repo: &Repository, generation: &Generation) -> Result<()> {
+    let record_path = generation.path.with_extension("json");
+    let json = serde_json::to_string_pretty(generation)
+        .map_err(|e| BackupError::io(&record_path, std::io::Error::other(e)))?;
+
+    let settings = repo.settings();
+    write_atomically(&record_path, settings.file_mode, settings.durability, |file, tmp_path| {
+        file.write_all(json.as_bytes()).map_err(|e| BackupError::io(tmp_path, e))
+    })
+}
+
+/// Parses the id out of a generation record name `<id>.json`.
+fn parse_id(file_name: &str) -> Option<u64> {
+    let id = file_name.strip_suffix(".json")?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    id.parse().ok()
+}
+
+/// Picks the generation of `filename` described by `selector`.
+pub(crate) fn select(repo: &Repository, filename: &str, selector: Selector) -> Result<Generation> {
+    let generations = list_generations(repo, filename)?;
+    let found = match selector {
+        Selector::Latest => generations.into_iter().next_back(),
+        Selector::Version(id) => generations.into_iter().find(|g| g.id == id),
+        Selector::At(time) => generations
+            .into_iter()
+            // Timestamps are given to the second, so compare at that precision.
+            .filter(|g| g.created.timestamp() <= time.timestamp())
+            .max_by_key(|g| (g.created, g.id)),
+    };
+
+    found.ok_or_else(|| BackupError::NoGeneration {
+        name: filename.to_string(),
+        selector,
+    })
+}
+
+/// Id and record path for the next generation of `filename`, creating the
+/// file's directory in `repo` if this is its first backup.
+pub(crate) fn next_generation(repo: &Repository, filename: &str) -> Result<(u64, PathBuf)> {
+    let next_id = list_generations(repo, filename)?.last().map_or(1, |g| g.id + 1);
+    let dir = repo.generations_dir(filename);
+    create_dir_all(&dir, repo.settings().dir_mode, repo.settings().durability)?;
+    Ok((next_id, dir.join(format!("{}.json", next_id))))
+}
+
+/// Parses a local timestamp for [`Selector::At`].
+///
+/// Accepts RFC 3339, `YYYY-MM-DD HH:MM[:SS]` (also with `T`) and a bare
+/// `YYYY-MM-DD`, which stands for the end of that day.
+pub fn parse_timestamp(text: &str) -> Option<DateTime<Local>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Some(time.with_timezone(&Local));
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(23, 59, 59))
+        })?;
+
+    Local.from_local_datetime(&naive).latest()
+}
+
+/// Like [`parse_timestamp`], but a bare `YYYY-MM-DD` stands for the start of
+/// that day, for the lower end of a range.
+pub fn parse_since(text: &str) -> Option<DateTime<Local>> {
+    match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(date) => Local.from_local_datetime(&date.and_hms_opt(0, 0, 0)?).earliest(),
+        Err(_) => parse_timestamp(text),
+    }
+}
+
+/// Like [`parse_timestamp`], but a bare `YYYY-MM-DD` stands for the start of
+/// the next day, for the upper end of a range that excludes it. Entries
+/// stamped with milliseconds late in the day then still fall inside.
+pub fn parse_until(text: &str) -> Option<DateTime<Local>> {
+    match NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        Ok(date) => Local.from_local_datetime(&date.succ_opt()?.and_hms_opt(0, 0, 0)?).earliest(),
+        Err(_) => parse_timestamp(text),
+    }
+}
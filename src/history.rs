@@ -0,0 +1,322 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BackupError, Result};
+use crate::logfile;
+use crate::repository::Repository;
+
+/// Which audit log entries [`query_log`] returns. Every filter set must
+/// match; an empty query matches everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogQuery {
+    /// A file or directory: a bare name matches every target with that
+    /// name, anything with a `/` only that path.
+    pub file: Option<String>,
+    /// Operations to include, such as `backup` or `restore`; empty for all.
+    pub operations: Vec<String>,
+    /// Only entries written at or after this time.
+    pub since: Option<DateTime<Local>>,
+    /// Only entries written before this time.
+    pub until: Option<DateTime<Local>>,
+    /// Only entries with this result, such as `success`.
+    pub result: Option<String>,
+}
+
+/// One entry of the audit log, from either format. Lines in the text format
+/// carry only the time and message, so who made the change is missing and
+/// the operation and target are taken from the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// Position in the hash chain; absent for unchained entries.
+    #[serde(default)]
+    pub seq: Option<u64>,
+    pub timestamp: DateTime<Local>,
+    pub operation: String,
+    /// Absolute path of the target in JSON entries; the name as given in
+    /// text ones.
+    pub target: String,
+    pub result: String,
+    /// Why the operation did not succeed.
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub bytes: Option<u64>,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub generation: Option<u64>,
+    pub message: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub pid: Option<u32>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+impl LogQuery {
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        self.file.as_deref().is_none_or(|file| matches_file(file, &record.target))
+            && (self.operations.is_empty()
+                || self.operations.iter().any(|op| op.eq_ignore_ascii_case(&record.operation)))
+            && self.since.is_none_or(|since| record.timestamp >= since)
+            && self.until.is_none_or(|until| record.timestamp < until)
+            && self.result.as_deref().is_none_or(|result| result.eq_ignore_ascii_case(&record.result))
+    }
+}
+
+/// Reads every rotated segment and the live audit log, oldest first, and
+/// returns the entries `query` matches. Lines that are neither a JSON entry
+/// nor a text entry are skipped; `log verify` reports them. A log that was
+/// never written has no entries.
+pub fn query_log(repo: &Repository, query: &LogQuery) -> Result<Vec<LogRecord>> {
+    let path = repo.log_path();
+    // Writers hold it exclusively, so no entry is read half-written and no
+    // segment is renamed while it is read.
+    let _lock = logfile::lock(repo, &path, false).map_err(|e| BackupError::io(&path, e))?;
+    let files = logfile::files(&path).map_err(|e| BackupError::io(&path, e))?;
+
+    let mut records = Vec::new();
+    for (file, codec) in &files {
+        let text = logfile::read(file, *codec).map_err(|e| BackupError::io(file, e))?;
+        for line in text.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            if let Some(record) = parse_line(&String::from_utf8_lossy(line)).filter(|r| query.matches(r)) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn parse_line(line: &str) -> Option<LogRecord> {
+    if line.starts_with('{') {
+        serde_json::from_str(line).ok()
+    } else {
+        parse_text(line)
+    }
+}
+
+/// Parses `[YYYY-MM-DD HH:MM:SS] Performed <operation> on <target>...`, or
+/// `Failed`, `Cancelled` or `Rejected` and the reason after a `: `, as
+/// written by the text format.
+fn parse_text(line: &str) -> Option<LogRecord> {
+    let (stamp, message) = line.strip_prefix('[')?.split_once("] ")?;
+    let naive = NaiveDateTime::parse_from_str(stamp, "%Y-%m-%d %H:%M:%S").ok()?;
+    let timestamp = Local.from_local_datetime(&naive).earliest()?;
+
+    let (verb, rest) = message.split_once(' ')?;
+    let result = match verb {
+        "Performed" => "success",
+        "Failed" => "failed",
+        "Cancelled" => "cancelled",
+        "Rejected" => "rejected",
+        _ => return None,
+    };
+    let end = rest.find([' ', ':']).unwrap_or(rest.len());
+    let operation = match &rest[..end] {
+        "recovery" => "recover",
+        operation => operation,
+    };
+    let (target, generation, reason) = match rest[end..].strip_prefix(" on ") {
+        Some(on) => {
+            let target_end = [" (generation ", ": "].iter().filter_map(|s| on.find(s)).min().unwrap_or(on.len());
+            let generation = on[target_end..]
+                .strip_prefix(" (generation ")
+                .and_then(|g| g.strip_suffix(')'))
+                .and_then(|g| g.parse().ok());
+            let reason = (result != "success").then(|| on[target_end..].strip_prefix(": ")).flatten();
+            (&on[..target_end], generation, reason)
+        }
+        None => ("", None, None),
+    };
+
+    Some(LogRecord {
+        seq: None,
+        timestamp,
+        operation: operation.to_string(),
+        target: target.to_string(),
+        result: result.to_string(),
+        reason: reason.map(str::to_string),
+        bytes: None,
+        digest: None,
+        generation,
+        message: message.to_string(),
+        user: None,
+        uid: None,
+        pid: None,
+        hostname: None,
+    })
+}
+
+/// Whether `target` is the file `file` names; see [`LogQuery::file`].
+fn matches_file(file: &str, target: &str) -> bool {
+    if file.contains('/') {
+        let file = std::path::absolute(file).unwrap_or_else(|_| PathBuf::from(file));
+        Path::new(target) == file
+    } else {
+        Path::new(target).file_name().is_some_and(|name| name == file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::generations::{parse_since, parse_until};
+
+    fn at(text: &str) -> DateTime<Local> {
+        let naive = NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    fn record(timestamp: &str, operation: &str, target: &str, result: &str) -> LogRecord {
+        parse_text(&format!("[2025-07-20 12:00:00] Performed {} on {}", operation, target))
+            .map(|record| LogRecord {
+                timestamp: at(timestamp),
+                result: result.to_string(),
+                ..record
+            })
+            .unwrap()
+    }
+
+    /// Which of `times` `query` lets through.
+    fn passing<'a>(query: &LogQuery, times: &[&'a str]) -> Vec<&'a str> {
+        times
+            .iter()
+            .filter(|time| query.matches(&record(time, "backup", "/data/notes.txt", "success")))
+            .copied()
+            .collect()
+    }
+
+    #[test]
+    fn bare_dates_cover_whole_days() {
+        assert_eq!(parse_since("2025-07-20"), Some(at("2025-07-20 00:00:00")));
+        assert_eq!(parse_until("2025-07-20"), Some(at("2025-07-21 00:00:00")));
+
+        let query = LogQuery {
+            since: parse_since("2025-07-20"),
+            until: parse_until("2025-07-20"),
+            ..LogQuery::default()
+        };
+        let times = [
+            "2025-07-19 23:59:59.999",
+            "2025-07-20 00:00:00",
+            "2025-07-20 23:59:59.999",
+            "2025-07-21 00:00:00",
+        ];
+        assert_eq!(passing(&query, &times), ["2025-07-20 00:00:00", "2025-07-20 23:59:59.999"]);
+    }
+
+    #[test]
+    fn full_timestamps_include_since_and_exclude_until() {
+        assert_eq!(parse_since("2025-07-20 12:00"), Some(at("2025-07-20 12:00:00")));
+        assert_eq!(parse_until("2025-07-20T12:00:30"), Some(at("2025-07-20 12:00:30")));
+
+        let query = LogQuery {
+            since: parse_since("2025-07-20 12:00:00"),
+            until: parse_until("2025-07-20 12:00:30"),
+            ..LogQuery::default()
+        };
+        let times = [
+            "2025-07-20 11:59:59.999",
+            "2025-07-20 12:00:00",
+            "2025-07-20 12:00:29.999",
+            "2025-07-20 12:00:30",
+        ];
+        assert_eq!(passing(&query, &times), ["2025-07-20 12:00:00", "2025-07-20 12:00:29.999"]);
+
+        let query = LogQuery {
+            until: Some(at("2025-07-20 12:00:00") + Duration::milliseconds(1)),
+            ..LogQuery::default()
+        };
+        assert_eq!(passing(&query, &times), ["2025-07-20 11:59:59.999", "2025-07-20 12:00:00"]);
+    }
+
+    #[test]
+    fn bare_name_matches_that_name_anywhere_and_a_path_only_itself() {
+        assert!(matches_file("notes.txt", "/data/notes.txt"));
+        assert!(matches_file("notes.txt", "/home/alice/notes.txt"));
+        assert!(matches_file("notes.txt", "notes.txt"));
+        assert!(!matches_file("notes.txt", "/data/notes.txt.bak"));
+        assert!(!matches_file("notes.txt", "/data/notes.txt/inner"));
+
+        assert!(matches_file("/data/notes.txt", "/data/notes.txt"));
+        assert!(!matches_file("/data/notes.txt", "/home/alice/notes.txt"));
+        let relative = std::env::current_dir().unwrap().join("sub/notes.txt");
+        assert!(matches_file("sub/notes.txt", &relative.to_string_lossy()));
+        assert!(matches_file("./sub/notes.txt", &relative.to_string_lossy()));
+        assert!(!matches_file("sub/notes.txt", "/data/notes.txt"));
+    }
+
+    #[test]
+    fn operation_and_result_filters_ignore_case() {
+        let time = "2025-07-20 12:00:00";
+        let records = [
+            record(time, "backup", "/data/a", "success"),
+            record(time, "restore", "/data/a", "success"),
+            record(time, "delete", "/data/a", "failed"),
+            record(time, "backup", "/data/b", "rejected"),
+        ];
+        let found = |query: LogQuery| -> Vec<usize> {
+            records.iter().enumerate().filter(|(_, r)| query.matches(r)).map(|(i, _)| i).collect()
+        };
+
+        assert_eq!(found(LogQuery::default()), [0, 1, 2, 3]);
+        assert_eq!(
+            found(LogQuery {
+                operations: vec!["BACKUP".to_string(), "delete".to_string()],
+                ..LogQuery::default()
+            }),
+            [0, 2, 3]
+        );
+        assert_eq!(
+            found(LogQuery {
+                result: Some("Success".to_string()),
+                ..LogQuery::default()
+            }),
+            [0, 1]
+        );
+        assert_eq!(
+            found(LogQuery {
+                file: Some("a".to_string()),
+                operations: vec!["backup".to_string()],
+                result: Some("success".to_string()),
+                ..LogQuery::default()
+            }),
+            [0]
+        );
+    }
+
+    #[test]
+    fn text_lines_give_operation_target_generation_and_reason() {
+        let line = parse_text("[2025-07-20 12:00:00] Performed backup on notes.txt (generation 3)").unwrap();
+        assert_eq!(line.timestamp, at("2025-07-20 12:00:00"));
+        assert_eq!((line.operation.as_str(), line.target.as_str()), ("backup", "notes.txt"));
+        assert_eq!((line.result.as_str(), line.generation, line.reason), ("success", Some(3), None));
+
+        let line =
+            parse_text("[2025-07-20 12:00:00] Failed restore on my notes.txt: File not found: my notes.txt").unwrap();
+        assert_eq!((line.operation.as_str(), line.target.as_str()), ("restore", "my notes.txt"));
+        assert_eq!(line.result, "failed");
+        assert_eq!(line.reason.as_deref(), Some("File not found: my notes.txt"));
+        assert_eq!(line.generation, None);
+
+        let line = parse_text("[2025-07-20 12:00:00] Rejected backup on ../x: Invalid file name: ../x").unwrap();
+        assert_eq!((line.result.as_str(), line.reason.as_deref()), ("rejected", Some("Invalid file name: ../x")));
+        let line = parse_text("[2025-07-20 12:00:00] Cancelled delete on notes.txt: Cancelled").unwrap();
+        assert_eq!((line.operation.as_str(), line.result.as_str()), ("delete", "cancelled"));
+        let line = parse_text("[2025-07-20 12:00:00] Performed recovery: removed 2 temp files").unwrap();
+        assert_eq!((line.operation.as_str(), line.target.as_str()), ("recover", ""));
+        let line = parse_text("[2025-07-20 12:00:00] Failed log-verify on audit.log: No such file").unwrap();
+        assert_eq!((line.operation.as_str(), line.target.as_str()), ("log-verify", "audit.log"));
+
+        assert_eq!(parse_text("[2025-07-20 12:00:00] Something else on notes.txt"), None);
+        assert_eq!(parse_text("[2025-07-20] Performed backup on notes.txt"), None);
+        assert_eq!(parse_text("Performed backup on notes.txt"), None);
+    }
+}
@@ -0,0 +1,80 @@
+//! SafeBackup engine: validated, atomic backup, restore and delete of files
+//! and directory trees, keeping every backup as a numbered generation inside
+//! a separate [`Repository`].
+//!
+//! The functions here never touch stdin or stdout. Every decision that used
+//! to be an interactive prompt is delegated to a [`Confirm`] implementation
+//! supplied by the caller, every operation returns a structured report, and
+//! every failure is a [`BackupError`]. Each attempt is written to the audit
+//! log with its outcome: failed, cancelled and rejected ones too.
+//!
+//! Unix only: metadata, file modes, locking and recovery use Unix
+//! interfaces throughout.
+
+#[cfg(not(unix))]
+compile_error!("safe_backup supports Unix targets only");
+
+mod audit;
+mod chunks;
+mod codec;
+mod confirm;
+mod crypt;
+mod digest;
+mod error;
+mod files;
+mod generations;
+mod history;
+mod lock;
+mod logfile;
+mod meta;
+mod ops;
+mod prune;
+mod recover;
+mod repository;
+mod sinks;
+mod trash;
+mod tree;
+mod verify;
+
+pub use audit::{log_rejected, verify_log, LogFormat, LogProblem, LogVerifyReport};
+pub use chunks::ChunkRef;
+pub use codec::{Codec, Compression};
+pub use confirm::{AssumeNo, AssumeYes, Confirm, Prompt};
+pub use crypt::{Encryption, Kdf, KeySource};
+pub use error::{BackupError, Result};
+pub use files::Durability;
+pub use generations::{list_generations, parse_since, parse_timestamp, parse_until, Generation, Selector};
+pub use history::{query_log, LogQuery, LogRecord};
+pub use logfile::{LogRotation, RotatePeriod};
+pub use meta::FileMetadata;
+pub use ops::{backup, delete, restore, BackupReport, DeleteReport, RestoreReport};
+pub use prune::{prune, PruneEntry, PruneReport, Retention};
+pub use recover::{recover, RecoveryAction, RecoveryEntry, RecoveryReport, RecoveryScope};
+pub use repository::{
+    parse_age, parse_size, parse_timeout, Config, Repository, Settings, DEFAULT_REPO, FORMAT_VERSION, REPO_ENV,
+};
+pub use sinks::{parse_facility, LogSink};
+pub use trash::{list_trash, undelete, TrashEntry, UndeleteReport};
+pub use tree::{EntryKind, TreeEntry};
+pub use verify::{verify, VerifyEntry, VerifyReport, VerifyStatus};
+
+pub const MAX_FILENAME_LENGTH: usize = 255;
+const VALID_CHAR: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_-.";
+
+/// Checks that `filename` is a plain file name in the working directory:
+/// non-empty, at most [`MAX_FILENAME_LENGTH`] bytes, no separators or `..`,
+/// and only characters from the whitelist.
+pub fn validate_filename(filename: &str) -> Result<()> {
+    let valid = !filename.is_empty()
+        && filename.len() <= MAX_FILENAME_LENGTH
+        && !filename.contains("..")
+        && !filename.contains('/')
+        && !filename.contains('\\')
+        && filename.chars().all(|c| VALID_CHAR.contains(c));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(BackupError::InvalidName(filename.to_string()))
+    }
+}
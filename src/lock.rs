@@ -0,0 +1,214 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{BackupError, Result};
+use crate::files::create_dir_all;
+use crate::repository::Repository;
+
+/// How often a lock held by someone else is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockMode {
+    /// Any number of holders, excluding [`LockMode::Exclusive`].
+    Shared,
+    /// One holder, which records its pid in the lock file.
+    Exclusive,
+}
+
+/// An advisory `flock` lock, released when dropped. Other runs of this tool
+/// honour it; other programs are not stopped by it.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    file: File,
+    mode: LockMode,
+}
+
+impl FileLock {
+    /// Locks the file `path` itself, if it exists, waiting as long as the
+    /// repository's `lock_timeout` allows. Used on the files a command reads
+    /// or replaces; `None` when there is nothing at `path` yet.
+    pub(crate) fn existing(repo: &Repository, path: &Path, mode: LockMode) -> Result<Option<FileLock>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(BackupError::io(path, e)),
+        };
+        acquire(file, path, mode, repo.settings().lock_timeout, false).map(Some)
+    }
+
+    /// Locks the lock file at `path`, creating it with the repository's
+    /// `file_mode` if needed.
+    pub(crate) fn lock_file(repo: &Repository, path: &Path, mode: LockMode) -> Result<FileLock> {
+        let settings = repo.settings();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(settings.file_mode)
+            .open(path)
+            .map_err(|e| BackupError::io(path, e))?;
+        let mut lock = acquire(file, path, mode, settings.lock_timeout, true)?;
+        if mode == LockMode::Exclusive {
+            lock.record_pid().map_err(|e| BackupError::io(path, e))?;
+        }
+        Ok(lock)
+    }
+
+    fn record_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        write!(self.file, "{}", process::id())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if self.mode == LockMode::Exclusive {
+            // So a later failure to lock does not name a process long gone.
+            let _ = self.file.set_len(0);
+        }
+    }
+}
+
+/// The locks every command that changes `name` holds: the repository,
+/// shared, so the `recover` command and pruning can take it exclusively,
+/// and the name's own lock file, so two runs never write the same
+/// generations at once.
+pub(crate) struct NameLocks {
+    _repository: FileLock,
+    _name: FileLock,
+}
+
+impl NameLocks {
+    pub(crate) fn acquire(repo: &Repository, name: &str) -> Result<NameLocks> {
+        let repository = repository_lock(repo, LockMode::Shared)?;
+        let settings = repo.settings();
+        let dir = repo.locks_dir();
+        create_dir_all(&dir, settings.dir_mode, settings.durability)?;
+        let name = FileLock::lock_file(repo, &dir.join(format!("{}.lock", name)), LockMode::Exclusive)?;
+        Ok(NameLocks {
+            _repository: repository,
+            _name: name,
+        })
+    }
+}
+
+/// Locks the repository as a whole.
+pub(crate) fn repository_lock(repo: &Repository, mode: LockMode) -> Result<FileLock> {
+    FileLock::lock_file(repo, &repo.lock_path(), mode)
+}
+
+/// Takes the lock on `file`, retrying until `timeout` runs out (forever when
+/// `None`). Only a dedicated lock file (`records_pid`) is read to name the
+/// holder.
+fn acquire(file: File, path: &Path, mode: LockMode, timeout: Option<Duration>, records_pid: bool) -> Result<FileLock> {
+    let try_lock = |file: &File| match mode {
+        LockMode::Shared => file.try_lock_shared(),
+        LockMode::Exclusive => file.try_lock(),
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        match try_lock(&file) {
+            Ok(()) => return Ok(FileLock { file, mode }),
+            Err(TryLockError::Error(e)) => return Err(BackupError::io(path, e)),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                return Err(BackupError::Locked {
+                    path: path.to_path_buf(),
+                    pid: if records_pid { holder(path) } else { None },
+                })
+            }
+            _ => thread::sleep(RETRY_INTERVAL),
+        }
+    }
+}
+
+/// The pid an exclusive holder wrote into the lock file, if any.
+fn holder(path: &Path) -> Option<u32> {
+    let mut text = String::new();
+    fs::File::open(path).ok()?.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn scratch_repo(timeout: Duration) -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_lock_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut repo = Repository::init(&dir).unwrap();
+        repo.settings_mut().lock_timeout = Some(timeout);
+        repo
+    }
+
+    #[test]
+    fn held_lock_times_out_naming_its_holder() {
+        let repo = scratch_repo(Duration::from_millis(100));
+        let _held = repository_lock(&repo, LockMode::Exclusive).unwrap();
+
+        let started = Instant::now();
+        let error = repository_lock(&repo, LockMode::Exclusive).unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(error.exit_code(), 18);
+        match error {
+            BackupError::Locked { path, pid } => {
+                assert_eq!(path, repo.lock_path());
+                assert_eq!(pid, Some(process::id()));
+            }
+            other => panic!("unexpected error {}", other),
+        }
+        assert!(matches!(
+            repository_lock(&repo, LockMode::Shared),
+            Err(BackupError::Locked { .. })
+        ));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn shared_locks_exclude_only_an_exclusive_one() {
+        let repo = scratch_repo(Duration::ZERO);
+        let first = repository_lock(&repo, LockMode::Shared).unwrap();
+        let second = repository_lock(&repo, LockMode::Shared).unwrap();
+        // A shared holder writes no pid, so none is named.
+        assert!(matches!(
+            repository_lock(&repo, LockMode::Exclusive),
+            Err(BackupError::Locked { pid: None, .. })
+        ));
+
+        drop(first);
+        drop(second);
+        drop(repository_lock(&repo, LockMode::Exclusive).unwrap());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn waiting_run_gets_the_lock_once_it_is_released() {
+        let repo = scratch_repo(Duration::from_secs(10));
+        let held = repository_lock(&repo, LockMode::Exclusive).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            drop(held);
+        });
+
+        assert!(repository_lock(&repo, LockMode::Exclusive).is_ok());
+        releaser.join().unwrap();
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+}
@@ -0,0 +1,498 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Datelike, Local};
+
+use crate::codec::{self, Codec, Compression};
+use crate::error::BackupError;
+use crate::files::{create_dir_all, sync_file, write_atomically};
+use crate::repository::Repository;
+
+/// When the audit log is closed and a new one started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotatePeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl RotatePeriod {
+    /// Parses `daily`, `weekly` or `monthly`; `never` is `Some(None)`.
+    pub fn parse(text: &str) -> Option<Option<RotatePeriod>> {
+        match text.trim().to_ascii_lowercase().as_str() {
+            "never" | "none" | "off" => Some(None),
+            "daily" => Some(Some(RotatePeriod::Daily)),
+            "weekly" => Some(Some(RotatePeriod::Weekly)),
+            "monthly" => Some(Some(RotatePeriod::Monthly)),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RotatePeriod::Daily => "daily",
+            RotatePeriod::Weekly => "weekly",
+            RotatePeriod::Monthly => "monthly",
+        }
+    }
+
+    /// The period `time` falls in, comparable with others of the same kind.
+    fn of(self, time: DateTime<Local>) -> (i32, u32) {
+        match self {
+            RotatePeriod::Daily => (time.year(), time.ordinal()),
+            RotatePeriod::Weekly => {
+                let week = time.iso_week();
+                (week.year(), week.week())
+            }
+            RotatePeriod::Monthly => (time.year(), time.month()),
+        }
+    }
+}
+
+impl fmt::Display for RotatePeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How the audit log is rotated. A rotated segment is renamed to
+/// `<log>.<YYYYMMDD-HHMMSS>`, then compressed if asked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LogRotation {
+    /// Start a new log before an entry would take it past this many bytes
+    /// (`log_max_size`).
+    pub max_size: Option<u64>,
+    /// Start a new log when the last entry was written in an earlier day,
+    /// week or month (`log_rotate`).
+    pub period: Option<RotatePeriod>,
+    /// Codec for rotated segments (`log_compress`).
+    pub compression: Compression,
+    /// How many rotated segments to keep; older ones are deleted
+    /// (`log_keep`). `None` keeps all of them.
+    pub keep: Option<usize>,
+}
+
+/// A rotated audit log segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub path: PathBuf,
+    /// `YYYYMMDD-HHMMSS` and a counter for segments rotated in the same
+    /// second; sorts oldest first.
+    order: (String, u32),
+    pub codec: Codec,
+}
+
+/// Appends one record to the repository's audit log. `line` is given the
+/// last complete line already in the log, even when that one has just been
+/// rotated away, and returns the record to write.
+///
+/// A lock file next to the log serializes writers, so every record is
+/// written whole, by one `write` on a file opened for appending, and no
+/// rotation happens under a writer.
+pub(crate) fn append(repo: &Repository, line: impl FnOnce(Option<&str>) -> io::Result<String>) -> io::Result<()> {
+    let settings = repo.settings();
+    let path = repo.log_path();
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        create_dir_all(parent, settings.dir_mode, settings.durability).map_err(into_io)?;
+    }
+    let _lock = lock(repo, &path, true)?;
+
+    let mut log = open(&path, settings.log_mode)?;
+    let (last, mut partial) = last_line(&mut log)?;
+    let line = line(last.as_deref())?;
+
+    let rotated = if should_rotate(&log, line.len() as u64 + 1, &settings.log_rotation)? {
+        drop(log);
+        let segment = rotate(&path)?;
+        log = open(&path, settings.log_mode)?;
+        partial = false;
+        Some(segment)
+    } else {
+        None
+    };
+
+    let mut record = Vec::with_capacity(line.len() + 2);
+    if partial {
+        // A write cut short; keep it on a line of its own.
+        record.push(b'\n');
+    }
+    record.extend_from_slice(line.as_bytes());
+    record.push(b'\n');
+    log.write_all(&record)?;
+    sync_file(&log, &path, settings.durability).map_err(into_io)?;
+
+    if let Some(segment) = rotated {
+        let rotation = &settings.log_rotation;
+        if rotation.compression.codec != Codec::None {
+            compress(repo, &segment, rotation.compression)?;
+        }
+        if let Some(keep) = rotation.keep {
+            let segments = segments(&path)?;
+            for old in &segments[..segments.len().saturating_sub(keep)] {
+                fs::remove_file(&old.path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every rotated segment of the log at `path`, oldest first.
+pub(crate) fn segments(path: &Path) -> io::Result<Vec<Segment>> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Ok(Vec::new());
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let read = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        read => read?,
+    };
+
+    let mut segments = Vec::new();
+    for entry in read {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(rest) = file_name.to_str().and_then(|n| n.strip_prefix(name)).and_then(|r| r.strip_prefix('.')) else {
+            continue;
+        };
+        let (rest, codec) = match rest.rsplit_once('.') {
+            Some((rest, ext)) => match codec_for_extension(ext) {
+                Some(codec) => (rest, codec),
+                None => continue,
+            },
+            None => (rest, Codec::None),
+        };
+        let (stamp, n) = match rest.split_at_checked(15) {
+            Some((stamp, "")) => (stamp, 0),
+            Some((stamp, n)) => match n.strip_prefix('-').and_then(|n| n.parse().ok()) {
+                Some(n) => (stamp, n),
+                None => continue,
+            },
+            None => continue,
+        };
+        let valid_stamp = stamp.bytes().enumerate().all(|(i, b)| if i == 8 { b == b'-' } else { b.is_ascii_digit() });
+        if valid_stamp {
+            segments.push(Segment {
+                path: dir.join(&file_name),
+                order: (stamp.to_string(), n),
+                codec,
+            });
+        }
+    }
+    segments.sort_by(|a, b| a.order.cmp(&b.order));
+    Ok(segments)
+}
+
+/// Every rotated segment of the log at `path`, oldest first, then the live
+/// log if there is one, with the codec each is read with.
+pub(crate) fn files(path: &Path) -> io::Result<Vec<(PathBuf, Codec)>> {
+    let mut files: Vec<(PathBuf, Codec)> =
+        segments(path)?.into_iter().map(|segment| (segment.path, segment.codec)).collect();
+    if path.exists() {
+        files.push((path.to_path_buf(), Codec::None));
+    }
+    Ok(files)
+}
+
+/// Reads a segment or the live log, decompressed.
+pub(crate) fn read(path: &Path, codec: Codec) -> io::Result<Vec<u8>> {
+    let mut text = Vec::new();
+    codec::decoder(codec, File::open(path)?)?.read_to_end(&mut text)?;
+    Ok(text)
+}
+
+/// Locks the log at `path` against rotation and writers: exclusively to
+/// write, shared to read.
+pub(crate) fn lock(repo: &Repository, path: &Path, exclusive: bool) -> io::Result<File> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(repo.settings().log_mode)
+        .open(lock_path)?;
+    if exclusive { file.lock()? } else { file.lock_shared()? }
+    Ok(file)
+}
+
+fn open(path: &Path, mode: u32) -> io::Result<File> {
+    OpenOptions::new().create(true).read(true).append(true).mode(mode).open(path)
+}
+
+/// The last complete line of `log`, and whether a partial line follows it.
+fn last_line(log: &mut File) -> io::Result<(Option<String>, bool)> {
+    let len = log.metadata()?.len();
+    let mut window = 4096;
+    loop {
+        let start = len.saturating_sub(window);
+        log.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        log.read_to_end(&mut tail)?;
+        let partial = tail.last().is_some_and(|&b| b != b'\n');
+        let newlines: Vec<usize> = tail.iter().enumerate().filter(|(_, b)| **b == b'\n').map(|(i, _)| i).collect();
+        let line = match newlines.as_slice() {
+            [.., before, end] => &tail[before + 1..*end],
+            [end] if start == 0 => &tail[..*end],
+            [] if start == 0 => return Ok((None, partial)),
+            _ => {
+                window *= 4;
+                continue;
+            }
+        };
+        return Ok((Some(String::from_utf8_lossy(line).into_owned()), partial));
+    }
+}
+
+/// Whether `log` must be rotated before `adding` more bytes go in.
+fn should_rotate(log: &File, adding: u64, rotation: &LogRotation) -> io::Result<bool> {
+    let metadata = log.metadata()?;
+    if metadata.len() == 0 {
+        return Ok(false);
+    }
+    if rotation.max_size.is_some_and(|max| metadata.len() + adding > max) {
+        return Ok(true);
+    }
+    Ok(match rotation.period {
+        Some(period) => {
+            let written = DateTime::<Local>::from(metadata.modified().unwrap_or(SystemTime::now()));
+            period.of(written) != period.of(Local::now())
+        }
+        None => false,
+    })
+}
+
+/// Renames the log at `path` to a new segment name and returns it. A
+/// segment rotated in the same second as an earlier one gets a counter past
+/// every one taken, so the names keep sorting in rotation order.
+fn rotate(path: &Path) -> io::Result<PathBuf> {
+    let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut name = path.as_os_str().to_owned();
+    match segments(path)?.iter().filter(|s| s.order.0 == stamp).map(|s| s.order.1).max() {
+        Some(n) => name.push(format!(".{}-{}", stamp, n + 1)),
+        None => name.push(format!(".{}", stamp)),
+    }
+    let segment = PathBuf::from(name);
+    fs::rename(path, &segment)?;
+    Ok(segment)
+}
+
+/// Replaces `segment` with a copy compressed with `compression`.
+fn compress(repo: &Repository, segment: &Path, compression: Compression) -> io::Result<()> {
+    let mut name = segment.as_os_str().to_owned();
+    name.push(format!(".{}", extension(compression.codec)));
+    let settings = repo.settings();
+    write_atomically(Path::new(&name), settings.log_mode, settings.durability, |file, tmp_path| {
+        let mut encoder = compression.encoder(file).map_err(|e| BackupError::io(tmp_path, e))?;
+        io::copy(&mut File::open(segment).map_err(|e| BackupError::io(segment, e))?, &mut encoder)
+            .and_then(|_| encoder.finish())
+            .map(|_| ())
+            .map_err(|e| BackupError::io(tmp_path, e))
+    })
+    .map_err(into_io)?;
+    fs::remove_file(segment)
+}
+
+fn extension(codec: Codec) -> &'static str {
+    match codec {
+        Codec::None => "",
+        Codec::Zstd => "zst",
+        Codec::Gzip => "gz",
+        Codec::Lz4 => "lz4",
+    }
+}
+
+fn codec_for_extension(ext: &str) -> Option<Codec> {
+    [Codec::Zstd, Codec::Gzip, Codec::Lz4].into_iter().find(|&codec| extension(codec) == ext)
+}
+
+/// Audit log failures are reported as I/O errors; see [`BackupError::Log`].
+fn into_io(e: BackupError) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use crate::audit::{log_action, verify_log, AuditEntry};
+
+    /// A new repository in a fresh directory, logging to `audit.log` inside
+    /// it and rotating as `rotation` says.
+    fn scratch_repo(rotation: LogRotation) -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_logfile_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut repo = Repository::init(&dir).unwrap();
+        repo.settings_mut().log_path = Some(PathBuf::from("audit.log"));
+        repo.settings_mut().log_rotation = rotation;
+        repo
+    }
+
+    /// Appends `count` lines of 40 bytes each, numbered from `first`.
+    fn append_lines(repo: &Repository, first: usize, count: usize) {
+        for n in first..first + count {
+            append(repo, |_| Ok(format!("line {:<34}", n))).unwrap();
+        }
+    }
+
+    /// The numbers of the lines in every segment and the live log, in order.
+    fn all_lines(repo: &Repository) -> Vec<usize> {
+        let mut numbers = Vec::new();
+        for (file, codec) in files(&repo.log_path()).unwrap() {
+            let text = String::from_utf8(read(&file, codec).unwrap()).unwrap();
+            numbers.extend(text.lines().map(|line| line[5..].trim().parse::<usize>().unwrap()));
+        }
+        numbers
+    }
+
+    fn stamp() -> String {
+        Local::now().format("%Y%m%d-%H%M%S").to_string()
+    }
+
+    #[test]
+    fn log_is_rotated_before_it_grows_past_max_size() {
+        let repo = scratch_repo(LogRotation {
+            max_size: Some(100),
+            ..LogRotation::default()
+        });
+        append_lines(&repo, 1, 5);
+
+        let segments = segments(&repo.log_path()).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|s| s.codec == Codec::None && fs::metadata(&s.path).unwrap().len() == 80));
+        assert_eq!(fs::metadata(repo.log_path()).unwrap().len(), 40);
+        assert_eq!(all_lines(&repo), vec![1, 2, 3, 4, 5]);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn log_written_in_an_earlier_period_is_rotated() {
+        let repo = scratch_repo(LogRotation {
+            period: Some(RotatePeriod::Daily),
+            ..LogRotation::default()
+        });
+        append_lines(&repo, 1, 2);
+        assert!(segments(&repo.log_path()).unwrap().is_empty());
+
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 3600);
+        File::options().write(true).open(repo.log_path()).unwrap().set_modified(two_days_ago).unwrap();
+        append_lines(&repo, 3, 2);
+
+        assert_eq!(segments(&repo.log_path()).unwrap().len(), 1);
+        assert_eq!(fs::read_to_string(repo.log_path()).unwrap().lines().count(), 2);
+        assert_eq!(all_lines(&repo), vec![1, 2, 3, 4]);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn segments_rotated_in_the_same_second_get_increasing_suffixes() {
+        let repo = scratch_repo(LogRotation::default());
+        let path = repo.log_path();
+        let name = |suffix: &str| format!("audit.log.{}", suffix);
+        // Retried in the rare case the clock turns a second in between.
+        let stamp = loop {
+            for segment in segments(&path).unwrap() {
+                fs::remove_file(segment.path).unwrap();
+            }
+            let before = stamp();
+            let mut rotated = Vec::new();
+            for _ in 0..2 {
+                append_lines(&repo, 1, 1);
+                rotated.push(rotate(&path).unwrap());
+            }
+            // A gap in the counters is skipped past, not filled.
+            fs::rename(&rotated[1], path.with_file_name(name(&format!("{}-5", before)))).unwrap();
+            append_lines(&repo, 1, 1);
+            rotate(&path).unwrap();
+            if stamp() == before {
+                break before;
+            }
+        };
+
+        let names: Vec<String> = segments(&path)
+            .unwrap()
+            .iter()
+            .map(|s| s.path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec![name(&stamp), name(&format!("{}-5", stamp)), name(&format!("{}-6", stamp))]
+        );
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn compressed_segments_read_back() {
+        for (codec, ext) in [("zstd", "zst"), ("gzip", "gz"), ("lz4", "lz4")] {
+            let repo = scratch_repo(LogRotation {
+                max_size: Some(100),
+                compression: Compression::parse(codec).unwrap(),
+                ..LogRotation::default()
+            });
+            append_lines(&repo, 1, 7);
+
+            let segments = segments(&repo.log_path()).unwrap();
+            assert_eq!(segments.len(), 3, "{}", codec);
+            for segment in &segments {
+                assert_eq!(segment.codec.as_str(), codec);
+                assert_eq!(segment.path.extension().unwrap(), ext);
+            }
+            assert_eq!(all_lines(&repo), vec![1, 2, 3, 4, 5, 6, 7], "{}", codec);
+            fs::remove_dir_all(repo.root()).unwrap();
+        }
+    }
+
+    #[test]
+    fn only_the_newest_segments_are_kept() {
+        let repo = scratch_repo(LogRotation {
+            max_size: Some(40),
+            compression: Compression::parse("gzip").unwrap(),
+            keep: Some(2),
+            ..LogRotation::default()
+        });
+        append_lines(&repo, 1, 6);
+
+        assert_eq!(segments(&repo.log_path()).unwrap().len(), 2);
+        assert_eq!(all_lines(&repo), vec![4, 5, 6]);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn chain_is_verified_across_compressed_segments() {
+        let repo = scratch_repo(LogRotation {
+            max_size: Some(1024),
+            compression: Compression::parse("zstd").unwrap(),
+            ..LogRotation::default()
+        });
+        for n in 1..=12 {
+            let target = format!("notes-{}.txt", n);
+            log_action(&repo, &AuditEntry::success("backup", Path::new(&target), format!("Backed up {}", target)))
+                .unwrap();
+        }
+
+        let report = verify_log(&repo).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(report.files.len() > 2);
+        assert!(report.files[0].extension().is_some_and(|ext| ext == "zst"));
+        assert_eq!(report.entries, 12);
+        assert_eq!(report.head.as_ref().map(|(seq, _)| *seq), Some(12));
+
+        // Losing a whole segment breaks the chain where the next one starts.
+        fs::remove_file(&report.files[1]).unwrap();
+        let report = verify_log(&repo).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].reason.contains("does not follow"));
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+}
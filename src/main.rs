@@ -1,222 +1,884 @@
+use std::env;
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::ffi::OsStr;
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::process;
-use chrono::Local;
-
+use std::time::Duration;
+
+use chrono::TimeDelta;
+
+use safe_backup::{
+    list_generations, log_rejected, parse_age, parse_since, parse_size, parse_timeout, parse_timestamp, parse_until,
+    query_log, validate_filename, BackupError, Compression, Confirm, Durability, Generation, KeySource, LogQuery,
+    LogRecord, LogVerifyReport, Prompt, PruneReport, RecoveryAction, RecoveryEntry, RecoveryScope, Repository,
+    Retention, Selector, TrashEntry, VerifyReport, VerifyStatus,
+};
+
+/// Environment variable holding the encryption passphrase.
+const PASSPHRASE_ENV: &str = "SAFE_BACKUP_PASSPHRASE";
+
+const USAGE: &str = "Usage: safe_backup_rust [OPTIONS] <COMMAND> [FILE]
+
+Commands:
+  init             Create the backup repository
+  backup <FILE>    Store FILE, or a directory and everything below it, as a
+                   new generation in the repository
+  restore <FILE>   Restore FILE (or directory) from its latest generation
+  list <FILE>      Show every generation of FILE
+  verify           Re-check every stored generation against its checksum
+                   (alias: scrub)
+  delete <FILE>    Move FILE to the repository's trash
+  undelete <FILE>  Bring back the most recently deleted FILE from the trash
+  trash list       Show every deleted file in the trash and when it expires
+  prune            Remove generations no retention rule keeps, and the
+                   chunks only they used
+  recover          Clean up after interrupted operations and report what
+                   was found (also done quietly before every command)
+  log [FILE]       Show audit log entries, of FILE or of every target,
+                   filtered by --op, --since, --until and --result
+  log verify       Check the audit log's hash chain for removed, reordered
+                   or altered entries
+
+Options:
+      --repo <PATH>     Backup repository (default: $SAFE_BACKUP_REPO, then .safe_backup)
+  -y, --yes             Answer yes to every confirmation prompt
+      --no-input        Never read from stdin; prompts are treated as declined
+      --version <ID>    restore: use generation ID
+      --at <TIME>       restore: use the newest generation at or before TIME
+                        (RFC 3339, YYYY-MM-DD HH:MM[:SS] or YYYY-MM-DD)
+      --report <PATH>   verify: write a JSON report to PATH (- for stdout)
+      --keep-last <N>   prune: keep the newest N generations of each file
+      --keep-daily <N>, --keep-weekly <N>, --keep-monthly <N>, --keep-yearly <N>
+                        prune: keep the newest generation of each of the last
+                        N days, weeks, months or years that have one
+      --keep-within <AGE>
+                        prune: keep everything younger than AGE (e.g. 36h,
+                        30d, 2w, 6mo, 1y)
+      --min-free <SIZE> prune: also remove the oldest kept generations until
+                        SIZE is free on the repository's filesystem (0 = off)
+      --dry-run         prune: only list what would be removed and why
+      --op <OPS>        log: only these operations (comma-separated, e.g.
+                        backup,restore)
+      --since <TIME>, --until <TIME>
+                        log: only entries written from --since up to,
+                        not including, --until (as for --at; a bare date
+                        covers the whole day)
+      --result <RESULT> log: only entries with this outcome (success, failed,
+                        cancelled or rejected)
+      --json            log: print the entries as a JSON array
+      --max-size <SIZE> Refuse files larger than SIZE (e.g. 500M, 2G; 0 = no
+                        limit), overriding max_file_size in the repository
+      --compress <CODEC[:LEVEL]>
+                        backup: compress with zstd, gzip, lz4 or none,
+                        overriding compression in the repository
+      --no-metadata     Do not record (backup) or reapply (restore) mode,
+                        ownership, times and extended attributes
+      --durability <LEVEL>
+                        How far writes are flushed to disk: full (file and
+                        directory, the default), data (file only) or none
+      --lock-timeout <SECS>
+                        How long to wait for another run's lock before
+                        failing (e.g. 30, 5m; 0 = fail at once, forever)
+      --log-file <PATH> Write the audit log to PATH (overrides log_file)
+      --log-key-file <PATH>
+                        Chain audit log entries with HMAC using the key in
+                        PATH, and check them with it in log verify
+                        (overrides log_key_file)
+      --key-file <PATH> Encrypt new backups with, and decrypt using, the
+                        32-byte key in PATH (overrides key_file)
+      --passphrase-file <PATH>
+                        Same, with a passphrase read from PATH; the
+                        SAFE_BACKUP_PASSPHRASE variable also sets one
+  -h, --help            Print this help
+
+Run without arguments for the interactive mode.
+
+Exit codes:
+  0  success              6  incomplete copy
+  2  usage error          7  cancelled
+  3  invalid filename     8  audit log failure
+  4  file not found       9  other I/O error
+  5  file too large      10  repository missing or already present
+                         11  unsupported repository format
+                         12  checksum mismatch
+                         13  verify found damaged backups
+                         14  invalid setting in repository config
+                         15  backup is encrypted, no key given
+                         16  wrong key or passphrase
+                         17  encrypted data failed authentication
+                         18  locked by another run
+                         19  audit log failed verification";
+
+#[derive(Clone, Copy)]
+enum Command {
+    Init,
+    Backup,
+    Restore,
+    List,
+    Verify,
+    Delete,
+    Prune,
+    Recover,
+    Undelete,
+    TrashList,
+    Log,
+    LogVerify,
+}
 
-const MAX_FILENAME_LENGTH: usize = 255;
-const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024; // 10MB
-const VALID_CHAR: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_-.";
+impl Command {
+    fn parse(command: &str) -> Option<Command> {
+        match command {
+            "init" => Some(Command::Init),
+            "backup" => Some(Command::Backup),
+            "restore" => Some(Command::Restore),
+            "list" => Some(Command::List),
+            "verify" | "scrub" => Some(Command::Verify),
+            "delete" => Some(Command::Delete),
+            "prune" => Some(Command::Prune),
+            "recover" => Some(Command::Recover),
+            "undelete" => Some(Command::Undelete),
+            _ => None,
+        }
+    }
 
-fn isValidFilename(filename: &str) -> bool {
-    if filename.is_empty() || filename.len() > MAX_FILENAME_LENGTH {
-        return false;
+    /// The operation name the audit log records; one word, so text lines
+    /// and syslog MSGIDs keep it whole.
+    fn name(self) -> &'static str {
+        match self {
+            Command::Init => "init",
+            Command::Backup => "backup",
+            Command::Restore => "restore",
+            Command::List => "list",
+            Command::Verify => "verify",
+            Command::Delete => "delete",
+            Command::Prune => "prune",
+            Command::Recover => "recover",
+            Command::Undelete => "undelete",
+            Command::TrashList => "trash-list",
+            Command::Log => "log",
+            Command::LogVerify => "log-verify",
+        }
     }
-    
-    if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
-        return false;
+
+    fn takes_file(self) -> bool {
+        !matches!(
+            self,
+            Command::Init
+                | Command::Verify
+                | Command::Prune
+                | Command::Recover
+                | Command::TrashList
+                | Command::Log
+                | Command::LogVerify
+        )
     }
-    
-    filename.chars().all(|c| VALID_CHAR.contains(c))
 }
 
-fn backupFile(filename: &str) -> io::Result<()> {
-    if !isValidFilename(filename) {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Invalid filename",
-        ));
-    }
-
-    let path = Path::new(filename);
-    if !path.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            "File not found",
-        ));
-    }
-
-    let metadata = fs::metadata(path)?;
-    if metadata.len() > MAX_FILE_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "File too large",
-        ));
-    }
-
-    let backupFilename = format!("{}.bak", filename);
-    let backupFilepath = Path::new(&backupFilename);
-
-    // Check if backup already exists
-    if backupFilepath.exists() {
-        println!("WARNING: Backup file {} already exists. Overwrite? (yes/no): ", backupFilename);
-        let mut confirm = String::new();
-        io::stdin().read_line(&mut confirm)?;
-        if confirm.trim().to_lowercase() != "yes" {
-            println!("Backup cancelled.");
-            return Ok(());
-        }
-    }
+/// How confirmation prompts are answered.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PromptMode {
+    /// Ask on stdin.
+    Ask,
+    /// Accept every prompt without asking (`--yes`).
+    AssumeYes,
+    /// Never read stdin; every prompt counts as declined (`--no-input`).
+    NoInput,
+}
 
-    let currPath = format!("{}.tmp", backupFilename);
-    {
-        let mut inputFile = fs::File::open(path)?;
-        let mut outputFile = fs::File::create(&currPath)?;
-        
-        // Set permissions (read/write for owner only)
-        let mut permissions = outputFile.metadata()?.permissions();
-        permissions.set_readonly(false);
-        fs::set_permissions(&currPath, permissions)?;
-
-        let bytes_copied = io::copy(&mut inputFile, &mut outputFile)?;
-        if bytes_copied != metadata.len() {
-            fs::remove_file(&currPath)?;
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Failed to copy entire file",
-            ));
+impl Confirm for PromptMode {
+    fn confirm(&mut self, prompt: &Prompt<'_>) -> bool {
+        let (prefix, suffix, expected) = match prompt {
+            Prompt::Delete { .. } => ("", "(type 'DELETE' to confirm):", "DELETE"),
+            _ => ("WARNING: ", "(yes/no):", "yes"),
+        };
+
+        match self {
+            PromptMode::AssumeYes => true,
+            PromptMode::NoInput => {
+                eprintln!("{}{} [declined: --no-input]", prefix, prompt);
+                false
+            }
+            PromptMode::Ask => {
+                println!("{}{} {} ", prefix, prompt, suffix);
+                let mut answer = String::new();
+                if io::stdin().read_line(&mut answer).is_err() {
+                    return false;
+                }
+                let answer = answer.trim();
+                match prompt {
+                    Prompt::Delete { .. } => answer == expected,
+                    _ => answer.to_lowercase() == expected,
+                }
+            }
         }
     }
+}
 
-    fs::rename(&currPath, backupFilepath)?;
-    println!("Backup created: {}", backupFilename);
-    logAction(&format!("Performed backup on {}", filename))?;
+/// Settings gathered from the command line (or defaults in interactive mode).
+struct Options {
+    prompt: PromptMode,
+    selector: Selector,
+    repo: Option<PathBuf>,
+    report: Option<PathBuf>,
+    /// `--max-size`; the inner `None` lifts the limit.
+    max_size: Option<Option<u64>>,
+    compression: Option<Compression>,
+    key: Option<KeySource>,
+    /// `--no-metadata`.
+    no_metadata: bool,
+    durability: Option<Durability>,
+    /// `--lock-timeout`; the inner `None` waits indefinitely.
+    lock_timeout: Option<Option<Duration>>,
+    /// `--keep-*`; each one set replaces its config value.
+    retention: Retention,
+    /// `--min-free`; the inner `None` turns it off.
+    min_free: Option<Option<u64>>,
+    dry_run: bool,
+    /// `log`: the file and the `--op`, `--since`, `--until` and `--result`
+    /// filters.
+    query: LogQuery,
+    json: bool,
+    log_key: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+}
 
-    Ok(())
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            prompt: PromptMode::Ask,
+            selector: Selector::Latest,
+            repo: None,
+            report: None,
+            max_size: None,
+            compression: None,
+            key: None,
+            no_metadata: false,
+            durability: None,
+            lock_timeout: None,
+            retention: Retention::default(),
+            min_free: None,
+            dry_run: false,
+            query: LogQuery::default(),
+            json: false,
+            log_key: None,
+            log_file: None,
+        }
+    }
 }
 
-fn restoreFile(filename: &str) -> io::Result<()> {
-    if !isValidFilename(filename) {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Invalid filename",
-        ));
+impl Options {
+    fn repo_path(&self) -> PathBuf {
+        Repository::locate(self.repo.as_deref())
     }
 
-    let backupFileName = format!("{}.bak", filename);
-    let backupFilePath = Path::new(&backupFileName);
+    /// Opens the repository and cleans up after any interrupted run first,
+    /// noting on stderr what was changed.
+    fn open_repo(&self, command: Command, filename: &str) -> Result<Repository, BackupError> {
+        let repo = self.load_repo(command, filename)?;
+        let report = safe_backup::recover(&repo, RecoveryScope::Startup)?;
+        for entry in report.entries.iter().filter(|e| e.action != RecoveryAction::InUse) {
+            eprintln!("Recovered: {}", describe_recovery(entry));
+        }
+        Ok(repo)
+    }
 
-    if !backupFilePath.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Backup file '{}' not found", backupFileName),
-        ));
+    /// Opens the repository and applies command-line overrides to its
+    /// settings. A repository that cannot be opened is logged as a failed
+    /// attempt at `command` on `filename`, or on the repository for commands
+    /// without a file.
+    fn load_repo(&self, command: Command, filename: &str) -> Result<Repository, BackupError> {
+        let mut repo = Repository::open(self.repo_path()).inspect_err(|e| {
+            let target = if command.takes_file() {
+                filename.to_string()
+            } else {
+                self.repo_path().to_string_lossy().into_owned()
+            };
+            self.log_refusal(command.name(), &target, e);
+        })?;
+        self.apply_overrides(&mut repo);
+        Ok(repo)
     }
 
-    let metadata = match fs::metadata(backupFilePath) {
-        Ok(m) => m,
-        Err(_) => {
-            return Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Cannot access backup file '{}'", backupFileName),
-            ));
-        }
-    };
+    /// The repository to log an attempt to that never got as far as opening
+    /// it: the configured one if possible, else one with default settings,
+    /// since the default audit log needs no repository.
+    fn audit_repo(&self) -> Repository {
+        let path = self.repo_path();
+        let mut repo = Repository::open(&path).unwrap_or_else(|_| Repository::unopened(path));
+        self.apply_overrides(&mut repo);
+        repo
+    }
 
-    if metadata.len() > MAX_FILE_SIZE {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Backup file too large",
-        ));
+    /// Records in the audit log that `operation` on `filename` was refused
+    /// before it started.
+    fn log_refusal(&self, operation: &'static str, filename: &str, error: &BackupError) {
+        if let Err(e) = log_rejected(&self.audit_repo(), operation, filename, error) {
+            eprintln!("Error: {}", e);
+        }
     }
 
-    if Path::new(filename).exists() {
-        println!("WARNING: Target file {} already exists. Overwrite? (yes/no): ", filename);
-        let mut confirm = String::new();
-        io::stdin().read_line(&mut confirm)?;
-        if confirm.trim().to_lowercase() != "yes" {
-            println!("Restore cancelled");
-            return Ok(());
+    /// Applies command-line overrides to the settings of `repo`.
+    fn apply_overrides(&self, repo: &mut Repository) {
+        if let Some(limit) = self.max_size {
+            repo.settings_mut().max_file_size = limit;
+        }
+        if let Some(compression) = self.compression {
+            repo.settings_mut().compression = compression;
+        }
+        if self.no_metadata {
+            repo.settings_mut().preserve_metadata = false;
+        }
+        if let Some(durability) = self.durability {
+            repo.settings_mut().durability = durability;
+        }
+        if let Some(timeout) = self.lock_timeout {
+            repo.settings_mut().lock_timeout = timeout;
+        }
+        let (given, retention) = (&self.retention, &mut repo.settings_mut().retention);
+        retention.keep_last = given.keep_last.or(retention.keep_last);
+        retention.keep_daily = given.keep_daily.or(retention.keep_daily);
+        retention.keep_weekly = given.keep_weekly.or(retention.keep_weekly);
+        retention.keep_monthly = given.keep_monthly.or(retention.keep_monthly);
+        retention.keep_yearly = given.keep_yearly.or(retention.keep_yearly);
+        retention.keep_within = given.keep_within.or(retention.keep_within);
+        if let Some(size) = self.min_free {
+            retention.min_free_space = size;
+        }
+        if let Some(path) = &self.log_file {
+            repo.settings_mut().log_path = Some(path.clone());
+        }
+        if let Some(path) = &self.log_key {
+            repo.settings_mut().log_key = Some(path.clone());
+        }
+        if let Some(key) = &self.key {
+            repo.settings_mut().key = Some(key.clone());
+        } else if let Some(passphrase) = env::var(PASSPHRASE_ENV).ok().filter(|p| !p.is_empty()) {
+            repo.settings_mut().key = Some(KeySource::Passphrase(passphrase));
         }
     }
+}
 
-    let currPath = format!("{}.tmp", filename);
-    {
-        let mut inputFile = fs::File::open(backupFilePath)?;
-        let mut outputFile = fs::File::create(&currPath)?;
-
-        let mut permissions = outputFile.metadata()?.permissions();
-        permissions.set_readonly(false);
-        fs::set_permissions(&currPath, permissions)?;
-
-        let byteCopied = io::copy(&mut inputFile, &mut outputFile)?;
-        if byteCopied != metadata.len() {
-            fs::remove_file(&currPath)?;
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Failed to copy entire file",
-            ));
+fn run_command(command: Command, filename: &str, options: &mut Options) -> Result<(), BackupError> {
+    let result = match command {
+        Command::Init => Repository::init(options.repo_path())
+            .map(|repo| println!("Initialized backup repository in {}", repo.root().display())),
+        Command::Backup => options.open_repo(command, filename).and_then(|repo| safe_backup::backup(&repo, filename)).map(|report| {
+            for path in &report.skipped {
+                eprintln!("Skipped {} (not a regular file or directory)", path.display());
+            }
+            if report.source.is_dir() {
+                println!("Captured {} files and {} directories", report.files, report.directories);
+            }
+            println!(
+                "Backup created: {} (generation {}, {} bytes in {} chunks, {} new chunks stored as {} bytes)",
+                report.backup.display(),
+                report.generation,
+                report.bytes,
+                report.chunks,
+                report.new_chunks,
+                report.stored_bytes
+            )
+        }),
+        Command::Restore => options
+            .open_repo(command, filename)
+            .and_then(|repo| safe_backup::restore(&repo, filename, options.selector, &mut options.prompt))
+            .map(|report| {
+                for warning in &report.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                println!("File restored from: {} (generation {})", report.backup.display(), report.generation)
+            }),
+        Command::List => options
+            .open_repo(command, filename)
+            .and_then(|repo| list_generations(&repo, filename))
+            .map(|generations| print_generations(filename, &generations)),
+        Command::Verify => options
+            .open_repo(command, filename)
+            .and_then(|repo| safe_backup::verify(&repo))
+            .and_then(|report| print_verify_report(&report, options.report.as_deref())),
+        Command::Delete => options
+            .open_repo(command, filename)
+            .and_then(|repo| safe_backup::delete(&repo, filename, &mut options.prompt))
+            .map(|report| println!("File moved to trash (entry {}; undelete to bring it back)", report.trash.id)),
+        Command::Prune => options
+            .open_repo(command, filename)
+            .and_then(|repo| safe_backup::prune(&repo, options.dry_run))
+            .map(|report| print_prune_report(&report)),
+        Command::Recover => options
+            .load_repo(command, filename)
+            .and_then(|repo| safe_backup::recover(&repo, RecoveryScope::Full))
+            .map(|report| {
+                for entry in &report.entries {
+                    println!("{}", describe_recovery(entry));
+                }
+                if report.entries.is_empty() {
+                    println!("Nothing to recover");
+                }
+            }),
+        Command::Undelete => options
+            .open_repo(command, filename)
+            .and_then(|repo| safe_backup::undelete(&repo, filename, &mut options.prompt))
+            .map(|report| {
+                for warning in &report.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                println!(
+                    "File restored from trash: {} (deleted {})",
+                    report.target.display(),
+                    report.entry.deleted.format("%Y-%m-%d %H:%M:%S")
+                )
+            }),
+        Command::TrashList => options.open_repo(command, filename).and_then(|repo| {
+            safe_backup::list_trash(&repo).map(|entries| print_trash(&entries, repo.settings().trash_expiry))
+        }),
+        Command::Log => options
+            .load_repo(command, filename)
+            .and_then(|repo| query_log(&repo, &options.query))
+            .and_then(|records| print_log_records(&records, options.json)),
+        Command::LogVerify => options
+            .load_repo(command, filename)
+            .and_then(|repo| safe_backup::verify_log(&repo))
+            .and_then(|report| print_log_verify_report(&report)),
+    };
+
+    if let Err(BackupError::Cancelled) = result {
+        match command {
+            Command::Restore => println!("Restore cancelled"),
+            Command::Delete => println!("Delete cancelled"),
+            Command::Undelete => println!("Undelete cancelled"),
+            Command::Init
+            | Command::Backup
+            | Command::List
+            | Command::Verify
+            | Command::Prune
+            | Command::Recover
+            | Command::TrashList
+            | Command::Log
+            | Command::LogVerify => {}
         }
     }
+    result
+}
 
-    fs::rename(&currPath, filename)?;
-    println!("File restored from: {}", backupFileName);
-    logAction(&format!("Performed restore on {}", filename))?;
+fn print_prune_report(report: &PruneReport) {
+    if report.entries.is_empty() {
+        println!("No backups to prune");
+    } else {
+        println!("{:<24}  {:>6}  {:<19}  {:<12}  REASON", "NAME", "ID", "CREATED", "ACTION");
+    }
+    for entry in &report.entries {
+        let action = match (entry.keep, report.dry_run) {
+            (true, _) => "keep",
+            (false, true) => "would remove",
+            (false, false) => "remove",
+        };
+        println!(
+            "{:<24}  {:>6}  {:<19}  {:<12}  {}",
+            entry.name,
+            entry.generation,
+            entry.created.format("%Y-%m-%d %H:%M:%S"),
+            action,
+            entry.reasons.join(", ")
+        );
+    }
 
-    Ok(())
+    let verb = if report.dry_run { "Would remove" } else { "Removed" };
+    println!(
+        "{} {} generations and {} chunks, freeing {} bytes",
+        verb,
+        report.removed().count(),
+        report.removed_chunks,
+        report.freed_bytes
+    );
+    if report.shortfall > 0 {
+        println!(
+            "Still {} bytes short of min_free_space; the newest generation of each file is always kept",
+            report.shortfall
+        );
+    }
+    for entry in &report.expired {
+        println!(
+            "{} {} from the trash (deleted {})",
+            if report.dry_run { "Would expire" } else { "Expired" },
+            entry.name,
+            entry.deleted.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
 }
 
-fn deleteFile(filename: &str) -> io::Result<()> {
-    if !isValidFilename(filename) {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Invalid filename",
-        ));
+fn print_trash(entries: &[TrashEntry], expiry: Option<TimeDelta>) {
+    if entries.is_empty() {
+        println!("The trash is empty");
+        return;
     }
 
-    let path = Path::new(filename);
-    if !path.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("File '{}' not found", filename),
-        ));
+    println!("{:>6}  {:<24}  {:>12}  {:<19}  {:<19}  ORIGINAL", "ID", "NAME", "SIZE", "DELETED", "EXPIRES");
+    for entry in entries {
+        let expires = match entry.expires(expiry) {
+            Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => "never".to_string(),
+        };
+        println!(
+            "{:>6}  {:<24}  {:>12}  {:<19}  {:<19}  {}",
+            entry.id,
+            entry.name,
+            entry.size,
+            entry.deleted.format("%Y-%m-%d %H:%M:%S"),
+            expires,
+            entry.original.display()
+        );
     }
+}
 
-    println!("Are you sure you want to delete {}? (type 'DELETE' to confirm): ", filename);
-    let mut confirm = String::new();
-    io::stdin().read_line(&mut confirm)?;
+fn describe_recovery(entry: &RecoveryEntry) -> String {
+    let origin = match entry.pid {
+        Some(pid) => format!("left by process {}", pid),
+        None => "left by an older version".to_string(),
+    };
+    let destination = entry.destination.as_deref().unwrap_or(Path::new("")).display();
+    match entry.action {
+        RecoveryAction::Removed => format!("Removed {} ({})", entry.path.display(), origin),
+        RecoveryAction::PutBack => format!("Put back {} from {} ({})", destination, entry.path.display(), origin),
+        RecoveryAction::Quarantined => format!("Quarantined {} as {}", entry.path.display(), destination),
+        RecoveryAction::InUse => format!("Skipped {} (still in use, {})", entry.path.display(), origin),
+    }
+}
+
+fn print_generations(filename: &str, generations: &[Generation]) {
+    if generations.is_empty() {
+        println!("No backups of {}", filename);
+        return;
+    }
 
-    if confirm.trim() == "DELETE" {
-        fs::remove_file(path)?;
+    println!("{:>6}  {:<4}  {:>12}  {:>12}  {:<5}  CREATED", "ID", "TYPE", "SIZE", "STORED", "CODEC");
+    for generation in generations {
+        println!(
+            "{:>6}  {:<4}  {:>12}  {:>12}  {:<5}  {}",
+            generation.id,
+            if generation.is_directory() { "dir" } else { "file" },
+            generation.size,
+            generation.stored_size.unwrap_or(generation.size),
+            generation.codec.as_str(),
+            generation.created.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+}
 
-        println!("File deleted");
+/// Prints problems and a summary, writes the JSON report if asked, and
+/// turns any damage into [`BackupError::Damaged`] for the exit code.
+fn print_verify_report(report: &VerifyReport, report_path: Option<&Path>) -> Result<(), BackupError> {
+    let json = serde_json::to_string_pretty(report).map_err(|e| BackupError::Io {
+        path: PathBuf::from("report"),
+        source: io::Error::other(e),
+    })?;
 
-        if let Err(e) = logAction(&format!("Performed delete on {}", filename)) {
-            eprintln!("Warning: Could not log delete action: {}", e);
+    match report_path {
+        Some(path) if path == Path::new("-") => println!("{}", json),
+        _ => {
+            for entry in report.entries.iter().filter(|e| e.status != VerifyStatus::Ok) {
+                println!(
+                    "{:<10} {} generation {} ({})",
+                    entry.status.as_str().to_uppercase(),
+                    entry.name,
+                    entry.generation,
+                    entry.path.display()
+                );
+            }
+            println!("{} generations checked, {} problems", report.checked, report.problems);
+
+            if let Some(path) = report_path {
+                fs::write(path, json + "\n").map_err(|e| BackupError::Io {
+                    path: path.to_path_buf(),
+                    source: e,
+                })?;
+            }
         }
+    }
 
+    if report.is_clean() {
         Ok(())
     } else {
-        println!("Delete cancelled");
-        Err(io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "Delete permission denied",
-        ))
+        Err(BackupError::Damaged {
+            problems: report.problems,
+        })
     }
 }
 
+/// Prints audit log entries as a table, or as a JSON array with `json`.
+fn print_log_records(records: &[LogRecord], json: bool) -> Result<(), BackupError> {
+    if json {
+        let json = serde_json::to_string_pretty(records).map_err(|e| BackupError::Io {
+            path: PathBuf::from("log"),
+            source: io::Error::other(e),
+        })?;
+        println!("{}", json);
+        return Ok(());
+    }
+    if records.is_empty() {
+        println!("No matching log entries");
+        return Ok(());
+    }
 
-fn logAction(action: &str) -> io::Result<()> {
-    
-    let sanitizeInput = action.replace("\n", " ").replace("\r", " ");
+    println!("{:<19}  {:<8}  {:<9}  {:<10}  {:<30}  DETAILS", "TIME", "OP", "RESULT", "USER", "TARGET");
+    for record in records {
+        let mut details = Vec::new();
+        if let Some(generation) = record.generation {
+            details.push(format!("generation {}", generation));
+        }
+        if let Some(bytes) = record.bytes {
+            details.push(format!("{} bytes", bytes));
+        }
+        details.extend(record.reason.clone());
+        let line = format!(
+            "{:<19}  {:<8}  {:<9}  {:<10}  {:<30}  {}",
+            record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            record.operation,
+            record.result,
+            record.user.as_deref().unwrap_or("-"),
+            if record.target.is_empty() { "-" } else { &record.target },
+            details.join(", ")
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
 
-    
-    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+/// Prints every break in the chain and a summary, turning any break into
+/// [`BackupError::LogTampered`] for the exit code.
+fn print_log_verify_report(report: &LogVerifyReport) -> Result<(), BackupError> {
+    for problem in &report.problems {
+        let at = format!("{}:{}", problem.file.display(), problem.line);
+        match problem.seq {
+            Some(seq) => println!("{} (seq {}): {}", at, seq, problem.reason),
+            None => println!("{}: {}", at, problem.reason),
+        }
+    }
+    if report.unchained > 0 {
+        println!("{} earlier lines predate the chain and were not checked", report.unchained);
+    }
+    if let Some(first) = report.first {
+        println!("The chain starts at seq {}; older segments were removed by log_keep", first);
+    }
+    println!(
+        "{} entries in {} files checked, {} problems",
+        report.entries,
+        report.files.len(),
+        report.problems.len()
+    );
+    if let Some((seq, hash)) = &report.head {
+        println!("Head: seq {} hash {}", seq, hash);
+    }
 
-    let mut log = fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("logfile.txt")?;
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(BackupError::LogTampered {
+            problems: report.problems.len(),
+        })
+    }
+}
 
-    writeln!(log, "[{}] {}", timestamp, sanitizeInput)?;
-    Ok(())
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
 }
 
+fn fail(e: BackupError) -> ! {
+    // run_command already told the user about a cancellation.
+    if !matches!(e, BackupError::Cancelled) {
+        eprintln!("Error: {}", e);
+    }
+    process::exit(e.exit_code());
+}
 
-fn main() {
+/// Parses `[OPTIONS] <COMMAND> [FILE]` and runs it without any extra stdin
+/// interaction beyond the prompts allowed by `--yes` / `--no-input`.
+fn run_args(args: &[String]) {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-y" | "--yes" => options.prompt = PromptMode::AssumeYes,
+            "--no-input" => {
+                if options.prompt != PromptMode::AssumeYes {
+                    options.prompt = PromptMode::NoInput;
+                }
+            }
+            "--repo" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--repo needs a path"));
+                options.repo = Some(PathBuf::from(value));
+            }
+            "--report" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--report needs a path"));
+                options.report = Some(PathBuf::from(value));
+            }
+            "--max-size" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--max-size needs a size"));
+                match parse_size(value) {
+                    Some(limit) => options.max_size = Some(limit),
+                    None => usage_error(&format!("Invalid size '{}'", value)),
+                }
+            }
+            "--no-metadata" => options.no_metadata = true,
+            "--durability" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--durability needs a level"));
+                match Durability::parse(value) {
+                    Some(durability) => options.durability = Some(durability),
+                    None => usage_error(&format!("Invalid durability '{}'", value)),
+                }
+            }
+            "--compress" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--compress needs a codec"));
+                match Compression::parse(value) {
+                    Some(compression) => options.compression = Some(compression),
+                    None => usage_error(&format!("Invalid compression '{}'", value)),
+                }
+            }
+            "--keep-last" | "--keep-daily" | "--keep-weekly" | "--keep-monthly" | "--keep-yearly" => {
+                let value = args.next().unwrap_or_else(|| usage_error(&format!("{} needs a count", arg)));
+                let count = value
+                    .parse()
+                    .unwrap_or_else(|_| usage_error(&format!("Invalid count '{}' for {}", value, arg)));
+                let rule = match arg.as_str() {
+                    "--keep-last" => &mut options.retention.keep_last,
+                    "--keep-daily" => &mut options.retention.keep_daily,
+                    "--keep-weekly" => &mut options.retention.keep_weekly,
+                    "--keep-monthly" => &mut options.retention.keep_monthly,
+                    _ => &mut options.retention.keep_yearly,
+                };
+                *rule = Some(count);
+            }
+            "--keep-within" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--keep-within needs an age"));
+                match parse_age(value) {
+                    Some(age) => options.retention.keep_within = Some(age),
+                    None => usage_error(&format!("Invalid age '{}'", value)),
+                }
+            }
+            "--min-free" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--min-free needs a size"));
+                match parse_size(value) {
+                    Some(size) => options.min_free = Some(size),
+                    None => usage_error(&format!("Invalid size '{}'", value)),
+                }
+            }
+            "--dry-run" => options.dry_run = true,
+            "--lock-timeout" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--lock-timeout needs a duration"));
+                match parse_timeout(value) {
+                    Some(timeout) => options.lock_timeout = Some(timeout),
+                    None => usage_error(&format!("Invalid lock timeout '{}'", value)),
+                }
+            }
+            "--log-file" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--log-file needs a path"));
+                // Relative to where the command runs, unlike `log_file`.
+                match std::path::absolute(value) {
+                    Ok(path) => options.log_file = Some(path),
+                    Err(e) => usage_error(&format!("Invalid log file '{}': {}", value, e)),
+                }
+            }
+            "--log-key-file" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--log-key-file needs a path"));
+                options.log_key = Some(PathBuf::from(value));
+            }
+            "--key-file" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--key-file needs a path"));
+                options.key = Some(KeySource::KeyFile(PathBuf::from(value)));
+            }
+            "--passphrase-file" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--passphrase-file needs a path"));
+                match fs::read_to_string(value) {
+                    Ok(text) => options.key = Some(KeySource::Passphrase(text.trim_end_matches(['\r', '\n']).to_string())),
+                    Err(e) => usage_error(&format!("Cannot read passphrase file '{}': {}", value, e)),
+                }
+            }
+            "--version" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--version needs a generation id"));
+                match value.parse() {
+                    Ok(id) => options.selector = Selector::Version(id),
+                    Err(_) => usage_error(&format!("Invalid generation id '{}'", value)),
+                }
+            }
+            "--at" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--at needs a timestamp"));
+                match parse_timestamp(value) {
+                    Some(time) => options.selector = Selector::At(time),
+                    None => usage_error(&format!("Invalid timestamp '{}'", value)),
+                }
+            }
+            "--op" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--op needs an operation"));
+                let operations = value.split(',').map(str::trim).filter(|op| !op.is_empty());
+                options.query.operations.extend(operations.map(str::to_ascii_lowercase));
+            }
+            "--since" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--since needs a timestamp"));
+                match parse_since(value) {
+                    Some(time) => options.query.since = Some(time),
+                    None => usage_error(&format!("Invalid timestamp '{}'", value)),
+                }
+            }
+            "--until" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--until needs a timestamp"));
+                match parse_until(value) {
+                    Some(time) => options.query.until = Some(time),
+                    None => usage_error(&format!("Invalid timestamp '{}'", value)),
+                }
+            }
+            "--result" => {
+                let value = args.next().unwrap_or_else(|| usage_error("--result needs an outcome"));
+                options.query.result = Some(value.to_ascii_lowercase());
+            }
+            "--json" => options.json = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option '{}'", arg)),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let (command, rest) = match positional.as_slice() {
+        ["log", "verify", rest @ ..] => (Command::LogVerify, rest),
+        ["log", rest @ ..] => (Command::Log, rest),
+        ["trash", "list", rest @ ..] => (Command::TrashList, rest),
+        ["trash", ..] => usage_error("Invalid trash command (expected `trash list`)"),
+        [name, rest @ ..] => (
+            Command::parse(name).unwrap_or_else(|| usage_error(&format!("Invalid command '{}'", name))),
+            rest,
+        ),
+        [] => usage_error("Missing command"),
+    };
+
+    let filename = match (command, command.takes_file(), rest) {
+        (_, true, [filename]) => *filename,
+        (_, false, []) => "",
+        // Any path the log may name, not only a file in this directory.
+        (Command::Log, _, [file]) => {
+            options.query.file = Some(file.to_string());
+            ""
+        }
+        _ => usage_error("Wrong number of arguments"),
+    };
+
+    if command.takes_file()
+        && let Err(e) = validate_filename(filename)
+    {
+        eprintln!("[REJECTED] Invalid filename: Potential path traversal or illegal characters.");
+        options.log_refusal(command.name(), filename, &e);
+        process::exit(e.exit_code());
+    }
+
+    if let Err(e) = run_command(command, filename, &mut options) {
+        fail(e);
+    }
+}
+
+fn run_interactive() {
     println!("Safe Backup - Rust");
 
     println!("Please enter your file name: ");
@@ -227,37 +889,42 @@ fn main() {
     }
     let filename = filename_input.trim();
 
-    if !isValidFilename(filename) {
+    if let Err(e) = validate_filename(filename) {
         eprintln!("\n[REJECTED] Invalid filename: Potential path traversal or illegal characters.");
+        // Rejected before a command was asked for.
+        Options::default().log_refusal("unknown", filename, &e);
         println!("\nPress Enter to exit...");
         let _ = io::stdin().read_line(&mut String::new());
-        process::exit(1);
+        process::exit(e.exit_code());
     }
 
-    println!("Enter your command (backup, restore, delete): ");
+    println!("Enter your command (backup, restore, list, delete, undelete): ");
     let mut command = String::new();
     if let Err(e) = io::stdin().read_line(&mut command) {
         eprintln!("Error reading command: {}", e);
         process::exit(1);
     }
-    let command = command.trim();
-
-    let result = match command {
-        "backup" => backupFile(filename),
-        "restore" => restoreFile(filename),
-        "delete" => deleteFile(filename),
+    let command = match Command::parse(command.trim()) {
+        Some(command) if command.takes_file() => command,
         _ => {
             eprintln!("Invalid command");
-            process::exit(1);
+            process::exit(2);
         }
     };
 
-    if let Err(e) = result {
-    eprintln!("Error: {}", e);
-    process::exit(1);
+    if let Err(e) = run_command(command, filename, &mut Options::default()) {
+        fail(e);
     }
 
-
     println!("\nPress Enter to exit...");
     let _ = io::stdin().read_line(&mut String::new());
 }
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        run_interactive();
+    } else {
+        run_args(&args);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::fs::{self, FileTimes, Permissions};
+use std::io;
+use std::os::unix::fs::{lchown, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::from_hex;
+use crate::digest::to_hex;
+use crate::error::{BackupError, Result};
+
+/// Attributes of a file or directory, captured at backup time and put back
+/// on restore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub accessed: DateTime<Local>,
+    pub modified: DateTime<Local>,
+    /// Extended attributes by name, values hex-encoded. Attributes whose
+    /// names are not UTF-8 are not kept.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// Reads the attributes of `path`, which is expected not to be a symlink.
+    pub(crate) fn capture(path: &Path) -> Result<FileMetadata> {
+        let metadata = fs::metadata(path).map_err(|e| BackupError::io(path, e))?;
+        let accessed = metadata.accessed().map_err(|e| BackupError::io(path, e))?;
+        let modified = metadata.modified().map_err(|e| BackupError::io(path, e))?;
+
+        let mut xattrs = BTreeMap::new();
+        let names = match xattr::list(path) {
+            Ok(names) => names.collect(),
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => Vec::new(),
+            Err(e) => return Err(BackupError::io(path, e)),
+        };
+        for name in names {
+            let Some(key) = name.to_str() else {
+                continue;
+            };
+            if let Some(value) = xattr::get(path, &name).map_err(|e| BackupError::io(path, e))? {
+                xattrs.insert(key.to_string(), to_hex(&value));
+            }
+        }
+
+        Ok(FileMetadata {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            accessed: accessed.into(),
+            modified: modified.into(),
+            xattrs,
+        })
+    }
+
+    /// Puts these attributes back on `path`. Ownership is only restored when
+    /// running as root; extended attributes the filesystem or the current
+    /// user cannot set are skipped and described in the returned warnings.
+    pub(crate) fn apply(&self, path: &Path) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
+        for (name, value) in &self.xattrs {
+            let value = from_hex(value).ok_or_else(|| {
+                BackupError::io(path, io::Error::new(io::ErrorKind::InvalidData, "malformed xattr value"))
+            })?;
+            match xattr::set(path, name, &value) {
+                Ok(()) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported) => {
+                    warnings.push(format!("{}: extended attribute {} not restored: {}", path.display(), name, e));
+                }
+                Err(e) => return Err(BackupError::io(path, e)),
+            }
+        }
+
+        // Before the mode, since changing the owner clears setuid and setgid.
+        if is_privileged() {
+            lchown(path, Some(self.uid), Some(self.gid)).map_err(|e| BackupError::io(path, e))?;
+        }
+
+        // Before the mode too, which may take away the access needed here.
+        let times = FileTimes::new()
+            .set_accessed(self.accessed.into())
+            .set_modified(self.modified.into());
+        fs::File::open(path)
+            .and_then(|file| file.set_times(times))
+            .map_err(|e| BackupError::io(path, e))?;
+
+        fs::set_permissions(path, Permissions::from_mode(self.mode)).map_err(|e| BackupError::io(path, e))?;
+        Ok(warnings)
+    }
+}
+
+fn is_privileged() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
@@ -0,0 +1,536 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use fastcdc::v2020::StreamCDC;
+use sha2::{Digest, Sha256};
+
+use crate::audit::{audited, log_action, AuditEntry};
+use crate::chunks::{ChunkRef, ChunkStore, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::codec;
+use crate::crypt::{is_auth_failure, DecryptReader, SealingKey};
+use crate::confirm::{Confirm, Prompt};
+use crate::digest::{to_hex, HashingReader};
+use crate::error::{BackupError, Result};
+use crate::files::{self, replace_dir, write_atomically, Durability, PRIVATE_DIR_MODE, PRIVATE_FILE_MODE};
+use crate::generations::{self, Generation, Selector};
+use crate::lock::{FileLock, LockMode, NameLocks};
+use crate::meta::FileMetadata;
+use crate::recover::PendingRestore;
+use crate::repository::Repository;
+use crate::trash::{self, TrashEntry};
+use crate::tree::{self, EntryKind, TreeEntry, TreeHasher};
+use crate::validate_filename;
+
+/// Size of the single buffer every copy streams through, so memory use stays
+/// the same whatever the file size.
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupReport {
+    pub source: PathBuf,
+    pub backup: PathBuf,
+    pub generation: u64,
+    pub bytes: u64,
+    /// Bytes newly written to the repository, after compression.
+    pub stored_bytes: u64,
+    /// Chunks making up the backup.
+    pub chunks: usize,
+    /// Chunks that were not already in the repository.
+    pub new_chunks: usize,
+    /// Files captured: 1 for a single file.
+    pub files: usize,
+    /// Subdirectories captured below a backed-up directory.
+    pub directories: usize,
+    /// Entries of a directory that were left out: symlinks, special files
+    /// and names that are not UTF-8.
+    pub skipped: Vec<PathBuf>,
+    /// Hex SHA-256 of a file, or the tree digest of a directory.
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestoreReport {
+    pub target: PathBuf,
+    pub backup: PathBuf,
+    pub generation: u64,
+    pub bytes: u64,
+    /// Metadata that could not be put back, such as extended attributes the
+    /// filesystem does not support.
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteReport {
+    pub target: PathBuf,
+    /// Where the file went; [`undelete`](crate::undelete) brings it back.
+    pub trash: TrashEntry,
+}
+
+/// Records `filename` as a new generation in `repo`. A directory is
+/// captured with everything below it (files, subdirectories and empty
+/// directories) as one snapshot; symlinks inside it are never followed.
+/// Unless [`Settings::preserve_metadata`](crate::Settings::preserve_metadata)
+/// is off, mode, ownership, times and extended attributes are recorded too.
+///
+/// Files are split into content-defined chunks, and chunks already in the
+/// repository are referenced rather than stored again, so a backup of a
+/// mostly unchanged file costs only its changed chunks. New chunks are
+/// compressed with the repository's configured codec and, when a key is
+/// configured, encrypted. Earlier generations are never touched.
+pub fn backup(repo: &Repository, filename: &str) -> Result<BackupReport> {
+    audited(repo, "backup", Path::new(filename), run_backup(repo, filename))
+}
+
+fn run_backup(repo: &Repository, filename: &str) -> Result<BackupReport> {
+    validate_filename(filename)?;
+    let _locks = NameLocks::acquire(repo, filename)?;
+
+    let path = Path::new(filename);
+    if !path.exists() {
+        return Err(BackupError::NotFound(path.to_path_buf()));
+    }
+    // Keeps a restore or delete by another run from replacing the source
+    // while it is read.
+    let _source = FileLock::existing(repo, path, LockMode::Shared)?;
+    let metadata = fs::metadata(path).map_err(|e| BackupError::io(path, e))?;
+    let preserve = repo.settings().preserve_metadata;
+    // Taken before reading, which may move the access time.
+    let root_metadata = preserve.then(|| FileMetadata::capture(path)).transpose()?;
+
+    let compression = repo.settings().compression;
+    let (key, encryption) = match &repo.settings().key {
+        Some(source) => {
+            let (key, encryption) = SealingKey::create(source, repo.kdf_salt()?, repo.root())?;
+            (Some(key), Some(encryption))
+        }
+        None => (None, None),
+    };
+    let store = ChunkStore::new(repo, compression, key);
+    let (id, record_path) = generations::next_generation(repo, filename)?;
+
+    let mut report = BackupReport {
+        source: path.to_path_buf(),
+        backup: record_path.clone(),
+        generation: id,
+        bytes: 0,
+        stored_bytes: 0,
+        chunks: 0,
+        new_chunks: 0,
+        files: 0,
+        directories: 0,
+        skipped: Vec::new(),
+        sha256: String::new(),
+    };
+    let (chunks, tree) = if metadata.is_dir() {
+        let scan = tree::scan(path, preserve)?;
+        let mut hasher = TreeHasher::new();
+        let mut entries = Vec::with_capacity(scan.found.len());
+        for found in scan.found {
+            let entry = match found.kind {
+                EntryKind::Directory => {
+                    report.directories += 1;
+                    TreeEntry {
+                        path: found.relative,
+                        kind: EntryKind::Directory,
+                        size: 0,
+                        sha256: None,
+                        chunks: Vec::new(),
+                        metadata: found.metadata,
+                    }
+                }
+                EntryKind::File => {
+                    let (chunks, size, sha256) = store_file(repo, &store, &found.path, &mut report)?;
+                    report.files += 1;
+                    TreeEntry {
+                        path: found.relative,
+                        kind: EntryKind::File,
+                        size,
+                        sha256: Some(sha256),
+                        chunks,
+                        metadata: found.metadata,
+                    }
+                }
+            };
+            hasher.add(entry.kind, &entry.path, entry.size, entry.sha256.as_deref());
+            entries.push(entry);
+        }
+        report.skipped = scan.skipped;
+        report.sha256 = hasher.finish();
+        (None, Some(entries))
+    } else {
+        let (chunks, _, sha256) = store_file(repo, &store, path, &mut report)?;
+        report.files = 1;
+        report.sha256 = sha256;
+        (Some(chunks), None)
+    };
+
+    generations::write_record(repo, &Generation {
+        id,
+        path: record_path,
+        size: report.bytes,
+        created: Local::now(),
+        sha256: report.sha256.clone(),
+        codec: compression.codec,
+        stored_size: Some(report.stored_bytes),
+        encryption,
+        chunks,
+        metadata: root_metadata,
+        tree,
+    })?;
+    let message = format!("Performed backup on {} (generation {})", filename, id);
+    log_action(repo, &AuditEntry {
+        bytes: Some(report.bytes),
+        digest: Some(report.sha256.clone()),
+        generation: Some(id),
+        ..AuditEntry::success("backup", path, message)
+    })
+    .map_err(BackupError::Log)?;
+
+    Ok(report)
+}
+
+/// Chunks one file into `store`, adding its counts to `report`, and returns
+/// its chunk list, size and hex SHA-256.
+fn store_file(
+    repo: &Repository,
+    store: &ChunkStore,
+    path: &Path,
+    report: &mut BackupReport,
+) -> Result<(Vec<ChunkRef>, u64, String)> {
+    let input = fs::File::open(path).map_err(|e| BackupError::io(path, e))?;
+    let expected = input.metadata().map_err(|e| BackupError::io(path, e))?.len();
+    check_size(repo, path, expected)?;
+
+    let mut input = HashingReader::new(input);
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    for data in StreamCDC::new(&mut input, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let data = data.map_err(|e| BackupError::io(path, e.into()))?;
+        let (chunk, written) = store.put(&data.data)?;
+        size += chunk.size;
+        if written > 0 {
+            report.stored_bytes += written;
+            report.new_chunks += 1;
+        }
+        chunks.push(chunk);
+    }
+    check_copied(path, expected, size)?;
+
+    report.bytes += size;
+    report.chunks += chunks.len();
+    Ok((chunks, size, input.finish()))
+}
+
+/// Copies the generation chosen by `selector` from `repo` back over
+/// `filename`, decrypting and decompressing as recorded. A file goes through
+/// a temp file and rename; a directory snapshot is rebuilt in a temp
+/// directory that then takes the place of `filename`.
+///
+/// Encrypted data is authenticated chunk by chunk before any of its
+/// plaintext is written, and only reaches `filename` once every chunk has
+/// passed; a wrong key gives [`BackupError::WrongKey`] and altered data
+/// [`BackupError::Tampered`]. The plaintext is also hashed on the way out;
+/// if any file no longer matches the digest recorded at backup time the live
+/// data is left untouched and [`BackupError::ChecksumMismatch`] is returned.
+/// Entry paths that would land outside `filename` are refused with
+/// [`BackupError::InvalidName`].
+///
+/// Recorded metadata is put back before the data takes the place of
+/// `filename`, unless
+/// [`Settings::preserve_metadata`](crate::Settings::preserve_metadata) is
+/// off; ownership only when running as root.
+pub fn restore(
+    repo: &Repository,
+    filename: &str,
+    selector: Selector,
+    confirm: &mut dyn Confirm,
+) -> Result<RestoreReport> {
+    audited(repo, "restore", Path::new(filename), run_restore(repo, filename, selector, confirm))
+}
+
+fn run_restore(
+    repo: &Repository,
+    filename: &str,
+    selector: Selector,
+    confirm: &mut dyn Confirm,
+) -> Result<RestoreReport> {
+    validate_filename(filename)?;
+    let _locks = NameLocks::acquire(repo, filename)?;
+
+    let generation = generations::select(repo, filename, selector)?;
+    match &generation.tree {
+        Some(entries) => {
+            for entry in entries {
+                check_size(repo, Path::new(&entry.path), entry.size)?;
+            }
+        }
+        None => check_size(repo, &generation.path, generation.size)?,
+    }
+
+    let target = Path::new(filename);
+    let _target = FileLock::existing(repo, target, LockMode::Exclusive)?;
+    if target.exists() && !confirm.confirm(&Prompt::OverwriteTarget { target }) {
+        return Err(BackupError::Cancelled);
+    }
+
+    let check_digest = |digest: String| {
+        if digest != generation.sha256 {
+            return Err(BackupError::ChecksumMismatch {
+                path: generation.path.clone(),
+                expected: generation.sha256.clone(),
+                actual: digest,
+            });
+        }
+        Ok(())
+    };
+    let durability = repo.settings().durability;
+    let preserve = repo.settings().preserve_metadata;
+    let mut warnings = Vec::new();
+    let mut apply = |metadata: Option<&FileMetadata>, path: &Path| match metadata {
+        Some(metadata) if preserve => {
+            warnings.extend(metadata.apply(path)?);
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+    let _pending = PendingRestore::record(repo, target)?;
+    let bytes = match &generation.tree {
+        Some(entries) => replace_dir(target, durability, |tmp_path| {
+            let (copied, digest) = read_tree(repo, &generation, Some(tmp_path))?;
+            check_digest(digest)?;
+            // Deepest first, so setting a directory's times comes after
+            // everything inside it has been written.
+            for entry in entries.iter().rev() {
+                apply(entry.metadata.as_ref(), &tmp_path.join(&entry.path))?;
+            }
+            apply(generation.metadata.as_ref(), tmp_path)?;
+            Ok(copied)
+        })?,
+        None => write_atomically(target, PRIVATE_FILE_MODE, durability, |output, tmp_path| {
+            let (copied, digest) = read_generation(repo, &generation, output, tmp_path)?;
+            check_copied(target, generation.size, copied)?;
+            check_digest(digest)?;
+            apply(generation.metadata.as_ref(), tmp_path)?;
+            Ok(copied)
+        })?,
+    };
+    let message = format!("Performed restore on {} (generation {})", filename, generation.id);
+    log_action(repo, &AuditEntry {
+        bytes: Some(bytes),
+        digest: Some(generation.sha256.clone()),
+        generation: Some(generation.id),
+        ..AuditEntry::success("restore", target, message)
+    })
+    .map_err(BackupError::Log)?;
+
+    Ok(RestoreReport {
+        target: target.to_path_buf(),
+        backup: generation.path,
+        generation: generation.id,
+        bytes,
+        warnings,
+    })
+}
+
+/// Moves `filename` into the repository's trash once the [`Confirm`] policy
+/// agrees, recording when it was deleted and, unless
+/// [`Settings::preserve_metadata`](crate::Settings::preserve_metadata) is
+/// off, its metadata. Entries older than
+/// [`Settings::trash_expiry`](crate::Settings::trash_expiry) are removed
+/// for good first.
+///
+/// A failure to write the audit entry does not undo the delete; it is
+/// reported as [`BackupError::Log`] after the file is already gone.
+pub fn delete(repo: &Repository, filename: &str, confirm: &mut dyn Confirm) -> Result<DeleteReport> {
+    audited(repo, "delete", Path::new(filename), run_delete(repo, filename, confirm))
+}
+
+fn run_delete(repo: &Repository, filename: &str, confirm: &mut dyn Confirm) -> Result<DeleteReport> {
+    validate_filename(filename)?;
+    let _locks = NameLocks::acquire(repo, filename)?;
+
+    let path = Path::new(filename);
+    if !path.exists() {
+        return Err(BackupError::NotFound(path.to_path_buf()));
+    }
+    let _target = FileLock::existing(repo, path, LockMode::Exclusive)?;
+
+    if !confirm.confirm(&Prompt::Delete { target: path }) {
+        return Err(BackupError::Cancelled);
+    }
+
+    trash::expire(repo, Local::now(), false)?;
+    let entry = trash::put(repo, filename, path)?;
+    let message = format!("Performed delete on {}", filename);
+    log_action(repo, &AuditEntry {
+        bytes: Some(entry.size),
+        ..AuditEntry::success("delete", path, message)
+    })
+    .map_err(BackupError::Log)?;
+
+    Ok(DeleteReport {
+        target: path.to_path_buf(),
+        trash: entry,
+    })
+}
+
+fn check_size(repo: &Repository, path: &Path, size: u64) -> Result<()> {
+    match repo.settings().max_file_size {
+        Some(limit) if size > limit => Err(BackupError::TooLarge {
+            path: path.to_path_buf(),
+            size,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Key for reading `generation`, if it is encrypted.
+fn open_key(repo: &Repository, generation: &Generation) -> Result<Option<SealingKey>> {
+    let Some(encryption) = &generation.encryption else {
+        return Ok(None);
+    };
+    let path = &generation.path;
+    let source = repo.settings().key.as_ref().ok_or_else(|| BackupError::KeyRequired(path.clone()))?;
+    SealingKey::open(source, encryption, path).map(Some)
+}
+
+/// Decrypts and decodes a file generation into `output` and returns the
+/// plaintext length and its hex SHA-256.
+pub(crate) fn read_generation(
+    repo: &Repository,
+    generation: &Generation,
+    output: &mut impl Write,
+    output_path: &Path,
+) -> Result<(u64, String)> {
+    let key = open_key(repo, generation)?;
+    match &generation.chunks {
+        Some(chunks) => read_chunks(&ChunkStore::new(repo, repo.settings().compression, key), chunks, output, output_path),
+        None => read_single_file(generation, key, output, output_path),
+    }
+}
+
+/// Decodes every entry of a directory generation and returns the total file
+/// size and the tree digest of what was read. With `into` the tree is
+/// rebuilt below that directory; without it the data is only checked.
+///
+/// Stops at the first file whose size or digest differs from its entry.
+pub(crate) fn read_tree(repo: &Repository, generation: &Generation, into: Option<&Path>) -> Result<(u64, String)> {
+    let store = ChunkStore::new(repo, repo.settings().compression, open_key(repo, generation)?);
+    let mut hasher = TreeHasher::new();
+    let mut total = 0;
+
+    for entry in generation.tree.iter().flatten() {
+        let relative = tree::entry_path(&entry.path)?;
+        let output_path = into.map(|root| root.join(&relative));
+        if entry.kind == EntryKind::Directory {
+            if let Some(dir) = &output_path {
+                files::create_dir_all(dir, PRIVATE_DIR_MODE, Durability::None)?;
+            }
+            hasher.add(entry.kind, &entry.path, 0, None);
+            continue;
+        }
+
+        let (copied, digest) = match &output_path {
+            Some(file_path) => {
+                if let Some(dir) = file_path.parent() {
+                    files::create_dir_all(dir, PRIVATE_DIR_MODE, Durability::None)?;
+                }
+                let mut file = files::create_new(file_path, PRIVATE_FILE_MODE)?;
+                let read = read_chunks(&store, &entry.chunks, &mut file, file_path)?;
+                files::sync_file(&file, file_path, repo.settings().durability)?;
+                read
+            }
+            None => read_chunks(&store, &entry.chunks, &mut io::sink(), Path::new("-"))?,
+        };
+        check_copied(&relative, entry.size, copied)?;
+        if entry.sha256.as_deref() != Some(digest.as_str()) {
+            return Err(BackupError::ChecksumMismatch {
+                path: relative,
+                expected: entry.sha256.clone().unwrap_or_default(),
+                actual: digest,
+            });
+        }
+
+        hasher.add(entry.kind, &entry.path, copied, Some(&digest));
+        total += copied;
+    }
+    Ok((total, hasher.finish()))
+}
+
+/// Writes `chunks` to `output` in order and returns their total length and
+/// hex SHA-256.
+pub(crate) fn read_chunks(
+    store: &ChunkStore,
+    chunks: &[ChunkRef],
+    output: &mut impl Write,
+    output_path: &Path,
+) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let mut copied = 0;
+    for chunk in chunks {
+        let data = store.get(chunk)?;
+        hasher.update(&data);
+        output.write_all(&data).map_err(|e| BackupError::io(output_path, e))?;
+        copied += data.len() as u64;
+    }
+    output.flush().map_err(|e| BackupError::io(output_path, e))?;
+    Ok((copied, to_hex(&hasher.finalize())))
+}
+
+/// Reads a generation stored as one `.bak` file, from before the chunk
+/// store.
+fn read_single_file(
+    generation: &Generation,
+    key: Option<SealingKey>,
+    output: &mut impl Write,
+    output_path: &Path,
+) -> Result<(u64, String)> {
+    let path = &generation.path;
+    let data = fs::File::open(path).map_err(|e| BackupError::io(path, e))?;
+    let raw: Box<dyn Read> = match key {
+        Some(key) => Box::new(DecryptReader::new(data, key)),
+        None => Box::new(data),
+    };
+
+    let decoder = codec::decoder(generation.codec, raw).map_err(|e| BackupError::io(path, e))?;
+    let mut input = HashingReader::new(decoder);
+    let copied = stream_copy(&mut input, path, output, output_path).map_err(|e| match e {
+        BackupError::Io { source, .. } if is_auth_failure(&source) => BackupError::Tampered(path.clone()),
+        e => e,
+    })?;
+    Ok((copied, input.finish()))
+}
+
+fn check_copied(path: &Path, expected: u64, copied: u64) -> Result<()> {
+    if copied != expected {
+        return Err(BackupError::ShortCopy {
+            path: path.to_path_buf(),
+            expected,
+            copied,
+        });
+    }
+    Ok(())
+}
+
+/// Streams `reader` into `writer` through one fixed-size buffer and returns
+/// the number of bytes moved. Errors name `from` or `to` depending on which
+/// side failed.
+fn stream_copy(reader: &mut impl Read, from: &Path, writer: &mut impl Write, to: &Path) -> Result<u64> {
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(BackupError::io(from, e)),
+        };
+        writer.write_all(&buffer[..n]).map_err(|e| BackupError::io(to, e))?;
+        total += n as u64;
+    }
+    writer.flush().map_err(|e| BackupError::io(to, e))?;
+    Ok(total)
+}
@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Datelike, Local, TimeDelta};
+
+use crate::audit::{audited, log_action, AuditEntry};
+use crate::chunks::{is_chunk_id, ChunkRef};
+use crate::error::{BackupError, Result};
+use crate::generations::{list_generations, Generation};
+use crate::lock::{repository_lock, LockMode};
+use crate::repository::Repository;
+use crate::trash::{self, TrashEntry};
+
+/// Which generations [`prune`] keeps. A generation is kept when any rule
+/// asks for it, and with no `keep_*` rule at all every generation is. The
+/// newest generation of every file is always kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// The newest N generations (`keep_last`).
+    pub keep_last: Option<usize>,
+    /// The newest generation of each of the last N days with one
+    /// (`keep_daily`); likewise for ISO weeks, months and years.
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+    /// Every generation younger than this (`keep_within`, e.g. `30d`).
+    pub keep_within: Option<TimeDelta>,
+    /// Free space to reach on the repository's filesystem
+    /// (`min_free_space`); when short, the oldest generations the rules
+    /// would keep are removed too.
+    pub min_free_space: Option<u64>,
+}
+
+impl Retention {
+    /// Whether any `keep_*` rule is set.
+    pub fn has_keep_rules(&self) -> bool {
+        Retention {
+            min_free_space: None,
+            ..self.clone()
+        } != Retention::default()
+    }
+}
+
+/// What [`prune`] decided for one generation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PruneEntry {
+    pub name: String,
+    pub generation: u64,
+    pub created: DateTime<Local>,
+    pub keep: bool,
+    /// The rules that keep it (`last 3`, `daily 2025-07-20`, ...), or why it
+    /// goes (`no rule keeps it`, `min_free_space`).
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PruneReport {
+    /// Nothing was removed; the report says what would have been.
+    pub dry_run: bool,
+    /// Every generation in the repository, by name and then id.
+    pub entries: Vec<PruneEntry>,
+    /// Chunks no kept generation refers to any more.
+    pub removed_chunks: usize,
+    /// Bytes of records, chunks and single-file data removed.
+    pub freed_bytes: u64,
+    /// Free space on the repository's filesystem before pruning.
+    pub free_space: u64,
+    /// How far `min_free_space` is still out of reach after pruning
+    /// everything the newest-generation rule allows.
+    pub shortfall: u64,
+    /// Deleted files removed from the trash by `trash_expiry`.
+    pub expired: Vec<TrashEntry>,
+}
+
+impl PruneReport {
+    pub fn removed(&self) -> impl Iterator<Item = &PruneEntry> {
+        self.entries.iter().filter(|e| !e.keep)
+    }
+}
+
+/// Applies the repository's [`Retention`] rules: expires the trash, removes
+/// the generations no rule keeps, then every chunk neither a remaining
+/// generation nor an encrypted file in the trash refers to. With `dry_run`
+/// only the report is produced.
+///
+/// Holds the repository lock exclusively, so no backup can come to depend on
+/// a chunk while it is being removed.
+pub fn prune(repo: &Repository, dry_run: bool) -> Result<PruneReport> {
+    audited(repo, "prune", repo.root(), run_prune(repo, dry_run))
+}
+
+fn run_prune(repo: &Repository, dry_run: bool) -> Result<PruneReport> {
+    let _lock = repository_lock(repo, LockMode::Exclusive)?;
+    let retention = &repo.settings().retention;
+    let now = Local::now();
+
+    // `generations[i]` is the one `entries[i]` decides on.
+    let mut generations = Vec::new();
+    let mut entries = Vec::new();
+    for name in repo.backed_up_names()? {
+        let mut of_name = list_generations(repo, &name)?;
+        of_name.reverse();
+        let reasons = keep_reasons(retention, &of_name, now);
+        for (generation, reasons) in of_name.iter().zip(reasons).rev() {
+            let keep = !reasons.is_empty();
+            entries.push(PruneEntry {
+                name: name.clone(),
+                generation: generation.id,
+                created: generation.created,
+                keep,
+                reasons: if keep { reasons } else { vec!["no rule keeps it".to_string()] },
+            });
+        }
+        generations.extend(of_name.into_iter().rev());
+    }
+
+    let free_space = free_space(repo.root()).map_err(|e| BackupError::io(repo.root(), e))?;
+    let expired = trash::expire(repo, now, dry_run)?;
+    let in_trash = trash::chunks_in_use(repo, &expired)?;
+    let stored = stored_chunks(repo)?;
+    let mut freed = Freed::new(&stored, &generations, &entries, &in_trash);
+    let mut shortfall = 0;
+    if let Some(target) = retention.min_free_space {
+        // Oldest first across every file, never the newest of one.
+        let mut candidates: Vec<usize> = (0..entries.len())
+            .filter(|&i| entries[i].keep && entries[i].reasons.iter().all(|r| r != NEWEST))
+            .collect();
+        candidates.sort_by_key(|&i| (entries[i].created, entries[i].generation));
+        let mut candidates = candidates.into_iter();
+        while free_space + freed.bytes < target {
+            let Some(i) = candidates.next() else {
+                shortfall = target - free_space - freed.bytes;
+                break;
+            };
+            entries[i].keep = false;
+            entries[i].reasons = vec![format!("min_free_space {}", target)];
+            freed.remove(&generations[i]);
+        }
+    }
+
+    let unreferenced = unreferenced(&stored, &generations, &entries, &in_trash);
+    let report = PruneReport {
+        dry_run,
+        removed_chunks: unreferenced.len(),
+        freed_bytes: freed.bytes,
+        free_space,
+        shortfall,
+        entries,
+        expired,
+    };
+    if dry_run {
+        return Ok(report);
+    }
+
+    // Records first: a generation without its record is gone, while a chunk
+    // left behind by an interruption is simply collected next time.
+    for (generation, entry) in generations.iter().zip(&report.entries) {
+        if !entry.keep {
+            remove_generation(generation)?;
+        }
+    }
+    for chunk in &unreferenced {
+        fs::remove_file(&chunk.path).map_err(|e| BackupError::io(&chunk.path, e))?;
+    }
+
+    let message = format!(
+        "Performed prune: removed {} generations and {} chunks, freed {} bytes",
+        report.removed().count(),
+        report.removed_chunks,
+        report.freed_bytes
+    );
+    log_action(repo, &AuditEntry {
+        bytes: Some(report.freed_bytes),
+        ..AuditEntry::success("prune", repo.root(), message)
+    })
+    .map_err(BackupError::Log)?;
+    Ok(report)
+}
+
+/// Reason that protects the newest generation of every file.
+const NEWEST: &str = "newest";
+
+/// Names the day, week, month or year a generation was created in.
+type PeriodOf = fn(&DateTime<Local>) -> String;
+
+/// The rules keeping each of `generations`, newest first.
+fn keep_reasons(retention: &Retention, generations: &[Generation], now: DateTime<Local>) -> Vec<Vec<String>> {
+    let mut reasons = vec![Vec::new(); generations.len()];
+    if let Some(first) = reasons.first_mut() {
+        first.push(NEWEST.to_string());
+    }
+    if !retention.has_keep_rules() {
+        for r in reasons.iter_mut() {
+            r.push("no keep rules set".to_string());
+        }
+        return reasons;
+    }
+
+    if let Some(n) = retention.keep_last {
+        for r in reasons.iter_mut().take(n) {
+            r.push(format!("last {}", n));
+        }
+    }
+
+    let periods: [(&str, Option<usize>, PeriodOf); 4] = [
+        ("daily", retention.keep_daily, |t| t.format("%Y-%m-%d").to_string()),
+        ("weekly", retention.keep_weekly, |t| {
+            let week = t.iso_week();
+            format!("{}-W{:02}", week.year(), week.week())
+        }),
+        ("monthly", retention.keep_monthly, |t| t.format("%Y-%m").to_string()),
+        ("yearly", retention.keep_yearly, |t| t.format("%Y").to_string()),
+    ];
+    for (rule, count, period_of) in periods {
+        let Some(count) = count else {
+            continue;
+        };
+        // Newest first, so the first generation seen in a period is the one
+        // that represents it.
+        let mut seen = HashSet::new();
+        for (generation, r) in generations.iter().zip(reasons.iter_mut()) {
+            if seen.len() == count {
+                break;
+            }
+            let period = period_of(&generation.created);
+            if seen.insert(period.clone()) {
+                r.push(format!("{} {}", rule, period));
+            }
+        }
+    }
+
+    if let Some(within) = retention.keep_within {
+        for (generation, r) in generations.iter().zip(reasons.iter_mut()) {
+            if now - generation.created <= within {
+                r.push(format!("within {}", format_age(within)));
+            }
+        }
+    }
+    reasons
+}
+
+/// A file in the chunk store.
+struct StoredChunk {
+    id: String,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Every chunk file in the repository.
+fn stored_chunks(repo: &Repository) -> Result<Vec<StoredChunk>> {
+    let mut stored = Vec::new();
+    for fan in read_dir(&repo.chunks_dir())? {
+        for path in read_dir(&fan)? {
+            let Some(id) = path.file_name().and_then(|n| n.to_str()).filter(|id| is_chunk_id(id)) else {
+                continue;
+            };
+            let size = fs::symlink_metadata(&path).map_err(|e| BackupError::io(&path, e))?.len();
+            stored.push(StoredChunk {
+                id: id.to_string(),
+                path,
+                size,
+            });
+        }
+    }
+    stored.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(stored)
+}
+
+/// The stored chunks neither a kept generation nor a file in the trash
+/// refers to, including chunks left by interrupted backups and deletes.
+fn unreferenced<'a>(
+    stored: &'a [StoredChunk],
+    generations: &[Generation],
+    entries: &[PruneEntry],
+    in_trash: &[ChunkRef],
+) -> Vec<&'a StoredChunk> {
+    let mut referenced: HashSet<&str> = in_trash.iter().map(|c| c.id.as_str()).collect();
+    for (generation, entry) in generations.iter().zip(entries) {
+        if entry.keep {
+            referenced.extend(generation.all_chunks().map(|c| c.id.as_str()));
+        }
+    }
+    stored.iter().filter(|chunk| !referenced.contains(chunk.id.as_str())).collect()
+}
+
+/// Bytes freed by removing generations, kept up to date as `min_free_space`
+/// removes more of them one at a time.
+struct Freed<'a> {
+    bytes: u64,
+    /// Size of each stored chunk.
+    sizes: HashMap<&'a str, u64>,
+    /// How often kept generations and the trash refer to each chunk.
+    references: HashMap<&'a str, usize>,
+}
+
+impl<'a> Freed<'a> {
+    /// The bytes freed by removing the generations `entries` do not keep:
+    /// their records and single-file data, and the chunks nothing kept
+    /// refers to.
+    fn new(
+        stored: &'a [StoredChunk],
+        generations: &'a [Generation],
+        entries: &[PruneEntry],
+        in_trash: &'a [ChunkRef],
+    ) -> Freed<'a> {
+        let mut sizes = HashMap::new();
+        for chunk in stored {
+            *sizes.entry(chunk.id.as_str()).or_insert(0) += chunk.size;
+        }
+        let mut references = HashMap::new();
+        for chunk in in_trash {
+            *references.entry(chunk.id.as_str()).or_insert(0) += 1;
+        }
+        let mut bytes = 0;
+        for (generation, entry) in generations.iter().zip(entries) {
+            if entry.keep {
+                for chunk in generation.all_chunks() {
+                    *references.entry(chunk.id.as_str()).or_insert(0) += 1;
+                }
+            } else {
+                bytes += files_size(generation);
+            }
+        }
+        bytes += sizes.iter().filter(|(id, _)| !references.contains_key(*id)).map(|(_, size)| size).sum::<u64>();
+        Freed {
+            bytes,
+            sizes,
+            references,
+        }
+    }
+
+    /// Adds what removing `generation`, so far kept, frees as well.
+    fn remove(&mut self, generation: &'a Generation) {
+        self.bytes += files_size(generation);
+        for chunk in generation.all_chunks() {
+            let Some(count) = self.references.get_mut(chunk.id.as_str()) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                self.references.remove(chunk.id.as_str());
+                self.bytes += self.sizes.get(chunk.id.as_str()).copied().unwrap_or(0);
+            }
+        }
+    }
+}
+
+/// Bytes of the record of `generation` and its single-file data.
+fn files_size(generation: &Generation) -> u64 {
+    generation_files(generation)
+        .iter()
+        .map(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        .sum()
+}
+
+/// The record of `generation` and, for an old single-file one, its data.
+fn generation_files(generation: &Generation) -> Vec<PathBuf> {
+    let record = generation.path.with_extension("json");
+    if generation.path == record {
+        vec![record]
+    } else {
+        vec![record, generation.path.clone()]
+    }
+}
+
+fn remove_generation(generation: &Generation) -> Result<()> {
+    for path in generation_files(generation) {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(BackupError::io(&path, e)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        entries => entries.map_err(|e| BackupError::io(dir, e))?,
+    };
+    entries
+        .map(|entry| entry.map(|e| e.path()).map_err(|e| BackupError::io(dir, e)))
+        .collect()
+}
+
+/// Bytes available to an unprivileged user on the filesystem holding `path`.
+fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is written on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded.
+    let stat = unsafe { stat.assume_init() };
+    // Both fields are narrower than u64 on some targets.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Formats an age the way [`parse_age`](crate::parse_age) reads it.
+fn format_age(age: TimeDelta) -> String {
+    let hours = age.num_hours();
+    if hours % 24 != 0 {
+        format!("{}h", hours)
+    } else {
+        format!("{}d", hours / 24)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generations::parse_timestamp;
+
+    /// Generations created at `times`, newest first, as `keep_reasons`
+    /// takes them.
+    fn generations(times: &[&str]) -> Vec<Generation> {
+        times
+            .iter()
+            .enumerate()
+            .map(|(i, time)| Generation {
+                id: (times.len() - i) as u64,
+                path: PathBuf::new(),
+                size: 0,
+                created: parse_timestamp(time).unwrap(),
+                sha256: String::new(),
+                codec: Default::default(),
+                stored_size: None,
+                encryption: None,
+                chunks: None,
+                metadata: None,
+                tree: None,
+            })
+            .collect()
+    }
+
+    fn reasons(retention: &Retention, times: &[&str], now: &str) -> Vec<Vec<String>> {
+        keep_reasons(retention, &generations(times), parse_timestamp(now).unwrap())
+    }
+
+    const NOW: &str = "2025-07-21 12:00";
+
+    #[test]
+    fn without_keep_rules_everything_is_kept() {
+        let reasons = reasons(&Retention::default(), &["2025-07-20 12:00", "2025-07-19 12:00"], NOW);
+        assert_eq!(reasons, vec![vec![NEWEST, "no keep rules set"], vec!["no keep rules set"]]);
+    }
+
+    #[test]
+    fn newest_is_kept_even_when_no_rule_asks_for_it() {
+        let retention = Retention {
+            keep_within: TimeDelta::try_hours(1),
+            ..Retention::default()
+        };
+        let reasons = reasons(&retention, &["2025-07-20 12:00", "2025-07-19 12:00"], NOW);
+        assert_eq!(reasons, vec![vec![NEWEST], vec![]]);
+    }
+
+    #[test]
+    fn last_keeps_the_newest_n() {
+        let retention = Retention {
+            keep_last: Some(2),
+            ..Retention::default()
+        };
+        let times = ["2025-07-20 12:00", "2025-07-19 12:00", "2025-07-18 12:00"];
+        let reasons = reasons(&retention, &times, NOW);
+        assert_eq!(reasons, vec![vec![NEWEST, "last 2"], vec!["last 2"], vec![]]);
+    }
+
+    #[test]
+    fn daily_keeps_the_newest_of_each_of_the_last_n_days() {
+        let retention = Retention {
+            keep_daily: Some(2),
+            ..Retention::default()
+        };
+        let times = ["2025-07-20 18:00", "2025-07-20 09:00", "2025-07-19 12:00", "2025-07-18 12:00"];
+        let reasons = reasons(&retention, &times, NOW);
+        assert_eq!(
+            reasons,
+            vec![vec![NEWEST, "daily 2025-07-20"], vec![], vec!["daily 2025-07-19"], vec![]]
+        );
+    }
+
+    #[test]
+    fn weekly_keeps_the_newest_of_each_of_the_last_n_iso_weeks() {
+        let retention = Retention {
+            keep_weekly: Some(2),
+            ..Retention::default()
+        };
+        // Sunday and Monday of week 29, then the Sundays of weeks 28 and 27.
+        let times = ["2025-07-20 12:00", "2025-07-14 12:00", "2025-07-13 12:00", "2025-07-06 12:00"];
+        let reasons = reasons(&retention, &times, NOW);
+        assert_eq!(
+            reasons,
+            vec![vec![NEWEST, "weekly 2025-W29"], vec![], vec!["weekly 2025-W28"], vec![]]
+        );
+    }
+
+    #[test]
+    fn within_keeps_everything_younger_than_the_age() {
+        let retention = Retention {
+            keep_within: TimeDelta::try_days(3),
+            ..Retention::default()
+        };
+        let times = ["2025-07-21 11:00", "2025-07-18 12:00", "2025-07-18 11:59"];
+        let reasons = reasons(&retention, &times, NOW);
+        assert_eq!(reasons, vec![vec![NEWEST, "within 3d"], vec!["within 3d"], vec![]]);
+    }
+}
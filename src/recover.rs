@@ -0,0 +1,342 @@
+use std::fs;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chrono::Local;
+
+use crate::audit::{audited, log_action, AuditEntry};
+use crate::error::{BackupError, Result};
+use crate::files::{create_dir_all, unique_tag, write_atomically, TempName};
+use crate::lock::{repository_lock, LockMode};
+use crate::repository::Repository;
+
+/// What [`recover`] did with one leftover of an interrupted operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryAction {
+    /// A temp file or directory that never became data; deleted.
+    Removed,
+    /// The copy an interrupted restore had moved aside, put back in place
+    /// because nothing replaced it.
+    PutBack,
+    /// Something that may hold data but belongs to no finished operation;
+    /// moved out of the way rather than deleted.
+    Quarantined,
+    /// Belongs to a process that is still running; left alone.
+    InUse,
+}
+
+impl RecoveryAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RecoveryAction::Removed => "removed",
+            RecoveryAction::PutBack => "put_back",
+            RecoveryAction::Quarantined => "quarantined",
+            RecoveryAction::InUse => "in_use",
+        }
+    }
+}
+
+/// How much of the repository [`recover`] looks through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryScope {
+    /// Everywhere except the chunk store, so the cost does not grow with
+    /// the data stored; the pass before every command.
+    Startup,
+    /// The chunk store's fan-out directories too, for the `recover`
+    /// command; holds the repository lock exclusively.
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryEntry {
+    pub path: PathBuf,
+    pub action: RecoveryAction,
+    /// Where the leftover was moved, for [`PutBack`](RecoveryAction::PutBack)
+    /// and [`Quarantined`](RecoveryAction::Quarantined).
+    pub destination: Option<PathBuf>,
+    /// Process that left it behind; `None` for temp files written before
+    /// they were tagged with one.
+    pub pid: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    pub entries: Vec<RecoveryEntry>,
+}
+
+impl RecoveryReport {
+    /// Whether anything was changed; leftovers still in use do not count.
+    pub fn is_clean(&self) -> bool {
+        self.entries.iter().all(|e| e.action == RecoveryAction::InUse)
+    }
+
+    fn add(&mut self, path: &Path, action: RecoveryAction, destination: Option<PathBuf>, pid: Option<u32>) {
+        self.entries.push(RecoveryEntry {
+            path: path.to_path_buf(),
+            action,
+            destination,
+            pid,
+        });
+    }
+}
+
+/// Notes the target of a restore in the repository until it is dropped, so
+/// that if the process dies the temp files it left next to the target can be
+/// found again.
+pub(crate) struct PendingRestore {
+    path: PathBuf,
+}
+
+impl PendingRestore {
+    pub(crate) fn record(repo: &Repository, target: &Path) -> Result<PendingRestore> {
+        let target = std::path::absolute(target).map_err(|e| BackupError::io(target, e))?;
+        let settings = repo.settings();
+        let dir = repo.pending_dir();
+        create_dir_all(&dir, settings.dir_mode, settings.durability)?;
+
+        let path = dir.join(unique_tag());
+        write_atomically(&path, settings.file_mode, settings.durability, |file, tmp_path| {
+            file.write_all(target.as_os_str().as_bytes()).map_err(|e| BackupError::io(tmp_path, e))
+        })?;
+        Ok(PendingRestore { path })
+    }
+}
+
+impl Drop for PendingRestore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Cleans up after operations that were interrupted by a crash or a kill.
+///
+/// Temp files whose process is gone are deleted from the directories of
+/// the repository that runs write them in; they never hold anything a
+/// finished generation depends on. For each restore
+/// that never finished, its temp file next to the target is deleted and a
+/// copy it had moved aside is put back if the target is missing, or else
+/// quarantined. Single-file data (`<id>.bak`) without a generation record
+/// comes from a backup that never finished and is quarantined under
+/// `<repo>/quarantine/`. Anything belonging to a process that is still
+/// running is left alone. Chunk temp files are only looked for with
+/// [`RecoveryScope::Full`].
+///
+/// Meant to run when a process starts, before it writes anything itself.
+pub fn recover(repo: &Repository, scope: RecoveryScope) -> Result<RecoveryReport> {
+    audited(repo, "recover", repo.root(), run_recover(repo, scope))
+}
+
+fn run_recover(repo: &Repository, scope: RecoveryScope) -> Result<RecoveryReport> {
+    // The startup pass only removes what dead processes left, so it can run
+    // beside other commands; the `recover` command waits for them to finish.
+    let mode = match scope {
+        RecoveryScope::Startup => LockMode::Shared,
+        RecoveryScope::Full => LockMode::Exclusive,
+    };
+    let _lock = repository_lock(repo, mode)?;
+    let mut report = RecoveryReport::default();
+    let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+
+    recover_restores(repo, &stamp, &mut report)?;
+    sweep(repo, scope, &stamp, &mut report)?;
+
+    let counts = [RecoveryAction::Removed, RecoveryAction::PutBack, RecoveryAction::Quarantined]
+        .map(|action| report.entries.iter().filter(|e| e.action == action).count());
+    if counts.iter().any(|&count| count > 0) {
+        let message = format!(
+            "Performed recovery: {} removed, {} put back, {} quarantined",
+            counts[0], counts[1], counts[2]
+        );
+        log_action(repo, &AuditEntry::success("recover", repo.root(), message)).map_err(BackupError::Log)?;
+    }
+    Ok(report)
+}
+
+/// Handles every restore noted in `<repo>/pending` whose process is gone.
+fn recover_restores(repo: &Repository, stamp: &str, report: &mut RecoveryReport) -> Result<()> {
+    let dir = repo.pending_dir();
+    for (path, name) in entries(&dir)? {
+        if TempName::parse(&name).is_some() {
+            // Half-written notes are swept with the rest of the repository.
+            continue;
+        }
+        let Some(pid) = name.split_once('-').and_then(|(pid, _)| pid.parse().ok()) else {
+            continue;
+        };
+        if is_running(pid) {
+            continue;
+        }
+
+        let text = fs::read(&path).map_err(|e| BackupError::io(&path, e))?;
+        let target = PathBuf::from(OsString::from_vec(text));
+        recover_target(&target, pid, stamp, report)?;
+        fs::remove_file(&path).map_err(|e| BackupError::io(&path, e))?;
+    }
+    Ok(())
+}
+
+/// Deals with what restore process `pid` left next to `target`.
+fn recover_target(target: &Path, pid: u32, stamp: &str, report: &mut RecoveryReport) -> Result<()> {
+    let (Some(parent), Some(target_name)) = (target.parent(), target.file_name()) else {
+        return Ok(());
+    };
+    let target_name = target_name.to_string_lossy();
+    let mut leftovers: Vec<(PathBuf, bool)> = entries(parent)?
+        .into_iter()
+        .filter_map(|(path, name)| {
+            let temp = TempName::parse(&name)?;
+            (temp.base == target_name && temp.pid == Some(pid)).then_some((path, temp.old))
+        })
+        .collect();
+    // New copies first, so the old one is only judged once they are gone.
+    leftovers.sort_by_key(|(_, old)| *old);
+
+    for (path, old) in leftovers {
+        if !old {
+            remove(&path)?;
+            report.add(&path, RecoveryAction::Removed, None, Some(pid));
+        } else if fs::symlink_metadata(target).is_err() {
+            fs::rename(&path, target).map_err(|e| BackupError::io(&path, e))?;
+            report.add(&path, RecoveryAction::PutBack, Some(target.to_path_buf()), Some(pid));
+        } else {
+            // The swap went through; the previous copy is kept for the user
+            // to look at, beside the target so no data crosses filesystems.
+            let destination = available(parent.join(format!("{}.recovered-{}", target_name, stamp)));
+            fs::rename(&path, &destination).map_err(|e| BackupError::io(&path, e))?;
+            report.add(&path, RecoveryAction::Quarantined, Some(destination), Some(pid));
+        }
+    }
+    Ok(())
+}
+
+/// Sweeps the directories of the repository that runs write temp files in:
+/// the root (the config), `pending/`, each name's generations, each trash
+/// entry and, for [`RecoveryScope::Full`], each chunk fan-out directory.
+fn sweep(repo: &Repository, scope: RecoveryScope, stamp: &str, report: &mut RecoveryReport) -> Result<()> {
+    sweep_dir(repo, repo.root(), false, stamp, report)?;
+    sweep_dir(repo, &repo.pending_dir(), false, stamp, report)?;
+    for dir in subdirs(&repo.backups_dir())? {
+        sweep_dir(repo, &dir, true, stamp, report)?;
+    }
+    for dir in subdirs(&repo.trash_dir())? {
+        sweep_dir(repo, &dir, false, stamp, report)?;
+    }
+    if scope == RecoveryScope::Full {
+        for dir in subdirs(&repo.chunks_dir())? {
+            sweep_dir(repo, &dir, false, stamp, report)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the abandoned temp files directly in `dir` and, when it holds
+/// the `generations` of a name, quarantines orphaned single-file data.
+///
+/// Only files are touched. No run leaves a temp directory in the
+/// repository, while a name such as `notes.tmp` keeps its generations in a
+/// directory that looks like one.
+fn sweep_dir(repo: &Repository, dir: &Path, generations: bool, stamp: &str, report: &mut RecoveryReport) -> Result<()> {
+    for (path, name) in entries(dir)? {
+        if let Some(temp) = TempName::parse(&name) {
+            match temp.pid {
+                Some(pid) if is_running(pid) => report.add(&path, RecoveryAction::InUse, None, Some(pid)),
+                pid if is_file(&path)? => {
+                    fs::remove_file(&path).map_err(|e| BackupError::io(&path, e))?;
+                    report.add(&path, RecoveryAction::Removed, None, pid);
+                }
+                _ => {}
+            }
+        } else if let Some(id) = name.strip_suffix(".bak")
+            && generations
+            && !id.is_empty()
+            && id.bytes().all(|b| b.is_ascii_digit())
+            && !dir.join(format!("{}.json", id)).exists()
+            && is_file(&path)?
+        {
+            let relative = path.strip_prefix(repo.root()).unwrap_or(&path);
+            let destination = available(repo.quarantine_dir().join(stamp).join(relative));
+            if let Some(parent) = destination.parent() {
+                let settings = repo.settings();
+                create_dir_all(parent, settings.dir_mode, settings.durability)?;
+            }
+            fs::rename(&path, &destination).map_err(|e| BackupError::io(&path, e))?;
+            report.add(&path, RecoveryAction::Quarantined, Some(destination), None);
+        }
+    }
+    Ok(())
+}
+
+/// The directories directly in `dir`; none if it is missing.
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for (path, _) in entries(dir)? {
+        if fs::symlink_metadata(&path).map_err(|e| BackupError::io(&path, e))?.is_dir() {
+            dirs.push(path);
+        }
+    }
+    Ok(dirs)
+}
+
+/// Whether `path` is a regular file; not if it has gone since it was listed.
+fn is_file(path: &Path) -> Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(metadata.is_file()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(BackupError::io(path, e)),
+    }
+}
+
+/// Paths and names of the entries of `dir`, sorted; none if it is missing.
+fn entries(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let read = match fs::read_dir(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        read => read.map_err(|e| BackupError::io(dir, e))?,
+    };
+    let mut entries = Vec::new();
+    for entry in read {
+        let entry = entry.map_err(|e| BackupError::io(dir, e))?;
+        entries.push((entry.path(), entry.file_name().to_string_lossy().into_owned()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Removes a leftover file or directory tree.
+fn remove(path: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(path).map_err(|e| BackupError::io(path, e))?;
+    if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+    .map_err(|e| BackupError::io(path, e))
+}
+
+/// `path`, or `path` with a counter appended if that is taken.
+fn available(path: PathBuf) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut n = 1;
+    while fs::symlink_metadata(&candidate).is_ok() {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        candidate = PathBuf::from(name);
+        n += 1;
+    }
+    candidate
+}
+
+/// Whether `pid` is another process that is still alive. This process never
+/// counts: anything tagged with its pid predates it.
+fn is_running(pid: u32) -> bool {
+    if pid == process::id() {
+        return false;
+    }
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists.
+    unsafe { libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
@@ -0,0 +1,592 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use chrono::{Local, TimeDelta};
+
+use crate::audit::LogFormat;
+use crate::codec::Compression;
+use crate::crypt::{random_salt, KeySource};
+use crate::error::{BackupError, Result};
+use crate::prune::Retention;
+use crate::logfile::{LogRotation, RotatePeriod};
+use crate::sinks::{parse_facility, LogSink, DEFAULT_FACILITY};
+use crate::files::{create_dir_all, sync_file, write_atomically, Durability, PRIVATE_DIR_MODE, PRIVATE_FILE_MODE};
+
+/// On-disk format written by [`Repository::init`].
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Environment variable naming the repository when `--repo` is not given.
+pub const REPO_ENV: &str = "SAFE_BACKUP_REPO";
+
+/// Repository used when neither `--repo` nor [`REPO_ENV`] is set.
+pub const DEFAULT_REPO: &str = ".safe_backup";
+
+/// How long a run waits for another one's lock unless `lock_timeout` says
+/// otherwise.
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Audit log used when `log_file` is not set, in the working directory.
+const DEFAULT_LOG_FILE: &str = "logfile.txt";
+
+const CONFIG_FILE: &str = "config";
+const BACKUPS_DIR: &str = "backups";
+const CHUNKS_DIR: &str = "chunks";
+const PENDING_DIR: &str = "pending";
+const LOCKS_DIR: &str = "locks";
+const LOCK_FILE: &str = "lock";
+const QUARANTINE_DIR: &str = "quarantine";
+const TRASH_DIR: &str = "trash";
+
+/// How long deleted files stay in the trash unless `trash_expiry` says.
+const DEFAULT_TRASH_EXPIRY: TimeDelta = TimeDelta::days(30);
+
+/// A backup repository, kept apart from the files it protects:
+///
+/// ```text
+/// <root>/config                   format stamp and settings, `key = value`
+/// <root>/backups/<name>/<id>.json generation <id> of file <name>
+/// <root>/chunks/<ab>/<ab...>      one stored chunk, named by its id
+/// <root>/lock                     lock on the whole repository
+/// <root>/locks/<name>.lock        lock on the generations of one file
+/// <root>/pending/<pid>-<n>        target of a restore still in progress
+/// <root>/quarantine/<time>/...    leftovers `recover` could not place
+/// <root>/trash/<id>/entry.json    a deleted file's name, time and metadata
+/// <root>/trash/<id>/data          the deleted file itself
+/// ```
+#[derive(Debug, Clone)]
+pub struct Repository {
+    root: PathBuf,
+    config: Config,
+    settings: Settings,
+    /// `kdf_salt` generated by this process for a repository that had none.
+    kdf_salt: OnceLock<String>,
+}
+
+impl Repository {
+    /// Creates an empty repository at `root` and stamps it with
+    /// [`FORMAT_VERSION`]. `root` may exist but must not hold a repository.
+    pub fn init(root: impl Into<PathBuf>) -> Result<Repository> {
+        let root = root.into();
+        let config_path = root.join(CONFIG_FILE);
+        if config_path.exists() {
+            return Err(BackupError::RepositoryExists(root));
+        }
+
+        let settings = Settings::default();
+        for dir in [BACKUPS_DIR, CHUNKS_DIR] {
+            create_dir_all(&root.join(dir), settings.dir_mode, settings.durability)?;
+        }
+
+        let mut config = Config::default();
+        config.set("format_version", &FORMAT_VERSION.to_string());
+        config.set("created", &Local::now().to_rfc3339());
+        config.set("kdf_salt", &random_salt());
+        config.write(&config_path, &settings)?;
+
+        Ok(Repository {
+            root,
+            config,
+            settings,
+            kdf_salt: OnceLock::new(),
+        })
+    }
+
+    /// Opens an existing repository, refusing formats this build cannot read.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Repository> {
+        let root = root.into();
+        let config_path = root.join(CONFIG_FILE);
+        if !config_path.is_file() {
+            return Err(BackupError::NoRepository(root));
+        }
+
+        let config = Config::read(&config_path)?;
+        let version = config.get("format_version").unwrap_or("");
+        if version.parse::<u32>().ok() != Some(FORMAT_VERSION) {
+            return Err(BackupError::UnsupportedFormat {
+                path: root,
+                version: version.to_string(),
+            });
+        }
+
+        let settings = Settings::from_config(&config)?;
+        Ok(Repository {
+            root,
+            config,
+            settings,
+            kdf_salt: OnceLock::new(),
+        })
+    }
+
+    /// A repository at `root` that could not be opened, with default
+    /// settings, so that the attempt can still go to the audit log. Nothing
+    /// at `root` is read or written.
+    pub fn unopened(root: impl Into<PathBuf>) -> Repository {
+        Repository {
+            root: root.into(),
+            config: Config::default(),
+            settings: Settings::default(),
+            kdf_salt: OnceLock::new(),
+        }
+    }
+
+    /// Resolves the repository location: `explicit`, else [`REPO_ENV`], else
+    /// [`DEFAULT_REPO`] in the working directory.
+    pub fn locate(explicit: Option<&Path>) -> PathBuf {
+        match explicit {
+            Some(path) => path.to_path_buf(),
+            None => env::var_os(REPO_ENV)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_REPO)),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Lets a front-end override configured settings for one run.
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    /// Directory holding one directory of generations per backed-up name.
+    pub(crate) fn backups_dir(&self) -> PathBuf {
+        self.root.join(BACKUPS_DIR)
+    }
+
+    /// Directory holding every generation of `name`.
+    pub(crate) fn generations_dir(&self, name: &str) -> PathBuf {
+        self.backups_dir().join(name)
+    }
+
+    /// Where the chunk with hex id `id` is stored; the first two digits fan
+    /// chunks out over 256 directories.
+    pub(crate) fn chunk_path(&self, id: &str) -> PathBuf {
+        self.chunks_dir().join(&id[..2]).join(id)
+    }
+
+    /// Directory holding the chunk store.
+    pub(crate) fn chunks_dir(&self) -> PathBuf {
+        self.root.join(CHUNKS_DIR)
+    }
+
+    /// The lock file for the repository as a whole.
+    pub(crate) fn lock_path(&self) -> PathBuf {
+        self.root.join(LOCK_FILE)
+    }
+
+    /// Directory of the per-file lock files.
+    pub(crate) fn locks_dir(&self) -> PathBuf {
+        self.root.join(LOCKS_DIR)
+    }
+
+    /// Where an in-progress restore notes its target, so leftovers next to
+    /// it can be found if the process dies.
+    pub(crate) fn pending_dir(&self) -> PathBuf {
+        self.root.join(PENDING_DIR)
+    }
+
+    /// The audit log: `log_file`, relative to the repository, or else
+    /// `logfile.txt` in the working directory.
+    pub(crate) fn log_path(&self) -> PathBuf {
+        match &self.settings.log_path {
+            Some(path) => self.root.join(path),
+            None => PathBuf::from(DEFAULT_LOG_FILE),
+        }
+    }
+
+    /// Where [`recover`](crate::recover) moves leftovers that might hold data.
+    pub(crate) fn quarantine_dir(&self) -> PathBuf {
+        self.root.join(QUARANTINE_DIR)
+    }
+
+    /// Where `delete` moves files, one directory per deleted file.
+    pub(crate) fn trash_dir(&self) -> PathBuf {
+        self.root.join(TRASH_DIR)
+    }
+
+    /// The Argon2id salt shared by every passphrase-encrypted generation, so
+    /// they derive one key and their chunks deduplicate. Repositories created
+    /// before chunking have none; one is generated and saved on first use.
+    pub(crate) fn kdf_salt(&self) -> Result<&str> {
+        if let Some(salt) = self.config.get("kdf_salt") {
+            return Ok(salt);
+        }
+        if let Some(salt) = self.kdf_salt.get() {
+            return Ok(salt);
+        }
+
+        // Appended rather than rewritten, so comments in the config survive.
+        // The lock and second look stop two runs each adding their own.
+        let config_path = self.root.join(CONFIG_FILE);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&config_path)
+            .map_err(|e| BackupError::io(&config_path, e))?;
+        let mut text = String::new();
+        file.lock()
+            .and_then(|()| file.read_to_string(&mut text))
+            .map_err(|e| BackupError::io(&config_path, e))?;
+        if let Some(salt) = Config::parse(&text).get("kdf_salt") {
+            let salt = salt.to_string();
+            return Ok(self.kdf_salt.get_or_init(|| salt));
+        }
+
+        let salt = random_salt();
+        writeln!(file, "kdf_salt = {}", salt).map_err(|e| BackupError::io(&config_path, e))?;
+        sync_file(&file, &config_path, self.settings.durability)?;
+        Ok(self.kdf_salt.get_or_init(|| salt))
+    }
+
+    /// Names of every file with at least one generation directory, sorted.
+    pub(crate) fn backed_up_names(&self) -> Result<Vec<String>> {
+        let dir = self.backups_dir();
+        let entries = fs::read_dir(&dir).map_err(|e| BackupError::io(&dir, e))?;
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| BackupError::io(&dir, e))?;
+            if entry.path().is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Behaviour read from the repository `config`. Keys that are absent keep
+/// the defaults.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Largest file `backup` and `restore` accept (`max_file_size`); `None`
+    /// means no limit.
+    pub max_file_size: Option<u64>,
+    /// Codec and level for new backups (`compression`, e.g. `zstd:19`).
+    pub compression: Compression,
+    /// Key for encrypting new backups and opening encrypted ones
+    /// (`key_file`). When set, every new backup is encrypted.
+    pub key: Option<KeySource>,
+    /// Capture mode, ownership, times and extended attributes on backup and
+    /// put them back on restore (`preserve_metadata`, on by default).
+    pub preserve_metadata: bool,
+    /// Permission bits for chunks, records and their temp files
+    /// (`file_mode`, octal, default `0600`). Set when a file is created;
+    /// the umask can only take bits away.
+    pub file_mode: u32,
+    /// Permission bits for directories created in the repository
+    /// (`dir_mode`, default `0700`).
+    pub dir_mode: u32,
+    /// Permission bits the audit log is created with (`log_mode`, default
+    /// `0600`).
+    pub log_mode: u32,
+    /// How audit log entries are written (`log_format`: `json`, the
+    /// default, or the older `text` lines).
+    pub log_format: LogFormat,
+    /// Where audit entries go (`log_sinks`, default `file`): the log file,
+    /// syslog, the journal, or several of them.
+    pub log_sinks: Vec<LogSink>,
+    /// Syslog facility code for the `syslog` sink (`syslog_facility`,
+    /// default `user`).
+    pub syslog_facility: u8,
+    /// Where the audit log is written (`log_file`); a relative path is taken
+    /// from the repository. `None` is `logfile.txt` in the working directory.
+    pub log_path: Option<PathBuf>,
+    /// When the audit log is rotated, how rotated segments are compressed
+    /// and how many are kept (`log_max_size`, `log_rotate`, `log_compress`,
+    /// `log_keep`).
+    pub log_rotation: LogRotation,
+    /// Key for chaining audit log entries with HMAC-SHA-256 instead of
+    /// plain SHA-256 (`log_key_file`, in the `key_file` format).
+    pub log_key: Option<PathBuf>,
+    /// How far writes are flushed before they count as done
+    /// (`durability`: `none`, `data` or the default `full`).
+    pub durability: Durability,
+    /// How long to wait for a lock held by another run before giving up
+    /// (`lock_timeout`, default 60 seconds); `None` waits indefinitely.
+    pub lock_timeout: Option<Duration>,
+    /// Which generations `prune` keeps (`keep_last`, `keep_daily`,
+    /// `keep_weekly`, `keep_monthly`, `keep_yearly`, `keep_within`,
+    /// `min_free_space`).
+    pub retention: Retention,
+    /// How long deleted files stay in the trash before `delete` or `prune`
+    /// removes them for good (`trash_expiry`, default `30d`); `None` keeps
+    /// them until undeleted.
+    pub trash_expiry: Option<TimeDelta>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            max_file_size: None,
+            compression: Compression::default(),
+            key: None,
+            preserve_metadata: true,
+            file_mode: PRIVATE_FILE_MODE,
+            dir_mode: PRIVATE_DIR_MODE,
+            log_mode: PRIVATE_FILE_MODE,
+            log_format: LogFormat::default(),
+            log_sinks: vec![LogSink::File],
+            syslog_facility: DEFAULT_FACILITY,
+            log_path: None,
+            log_rotation: LogRotation::default(),
+            log_key: None,
+            durability: Durability::default(),
+            lock_timeout: Some(DEFAULT_LOCK_TIMEOUT),
+            retention: Retention::default(),
+            trash_expiry: Some(DEFAULT_TRASH_EXPIRY),
+        }
+    }
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> Result<Settings> {
+        let mut settings = Settings::default();
+        if let Some(value) = config.get("max_file_size") {
+            settings.max_file_size = parse_size(value).ok_or_else(|| invalid_setting("max_file_size", value))?;
+        }
+        if let Some(value) = config.get("compression") {
+            settings.compression = Compression::parse(value).ok_or_else(|| invalid_setting("compression", value))?;
+        }
+        if let Some(value) = config.get("key_file") {
+            settings.key = Some(KeySource::KeyFile(PathBuf::from(value)));
+        }
+        if let Some(value) = config.get("preserve_metadata") {
+            settings.preserve_metadata = parse_bool(value).ok_or_else(|| invalid_setting("preserve_metadata", value))?;
+        }
+        if let Some(value) = config.get("log_format") {
+            settings.log_format = LogFormat::parse(value).ok_or_else(|| invalid_setting("log_format", value))?;
+        }
+        if let Some(value) = config.get("log_sinks") {
+            settings.log_sinks = LogSink::parse_list(value).ok_or_else(|| invalid_setting("log_sinks", value))?;
+        }
+        if let Some(value) = config.get("syslog_facility") {
+            settings.syslog_facility = parse_facility(value).ok_or_else(|| invalid_setting("syslog_facility", value))?;
+        }
+        if let Some(value) = config.get("log_file") {
+            settings.log_path = Some(PathBuf::from(value));
+        }
+        let rotation = &mut settings.log_rotation;
+        if let Some(value) = config.get("log_max_size") {
+            rotation.max_size = parse_size(value).ok_or_else(|| invalid_setting("log_max_size", value))?;
+        }
+        if let Some(value) = config.get("log_rotate") {
+            rotation.period = RotatePeriod::parse(value).ok_or_else(|| invalid_setting("log_rotate", value))?;
+        }
+        if let Some(value) = config.get("log_compress") {
+            rotation.compression = Compression::parse(value).ok_or_else(|| invalid_setting("log_compress", value))?;
+        }
+        if let Some(value) = config.get("log_keep") {
+            rotation.keep = Some(value.trim().parse().map_err(|_| invalid_setting("log_keep", value))?);
+        }
+        if let Some(value) = config.get("log_key_file") {
+            settings.log_key = Some(PathBuf::from(value));
+        }
+        if let Some(value) = config.get("durability") {
+            settings.durability = Durability::parse(value).ok_or_else(|| invalid_setting("durability", value))?;
+        }
+        if let Some(value) = config.get("lock_timeout") {
+            settings.lock_timeout = parse_timeout(value).ok_or_else(|| invalid_setting("lock_timeout", value))?;
+        }
+        let retention = &mut settings.retention;
+        for (key, count) in [
+            ("keep_last", &mut retention.keep_last),
+            ("keep_daily", &mut retention.keep_daily),
+            ("keep_weekly", &mut retention.keep_weekly),
+            ("keep_monthly", &mut retention.keep_monthly),
+            ("keep_yearly", &mut retention.keep_yearly),
+        ] {
+            if let Some(value) = config.get(key) {
+                *count = Some(value.trim().parse().map_err(|_| invalid_setting(key, value))?);
+            }
+        }
+        if let Some(value) = config.get("keep_within") {
+            retention.keep_within = Some(parse_age(value).ok_or_else(|| invalid_setting("keep_within", value))?);
+        }
+        if let Some(value) = config.get("min_free_space") {
+            retention.min_free_space = parse_size(value).ok_or_else(|| invalid_setting("min_free_space", value))?;
+        }
+        if let Some(value) = config.get("trash_expiry") {
+            settings.trash_expiry = parse_expiry(value).ok_or_else(|| invalid_setting("trash_expiry", value))?;
+        }
+        for (key, mode) in [
+            ("file_mode", &mut settings.file_mode),
+            ("dir_mode", &mut settings.dir_mode),
+            ("log_mode", &mut settings.log_mode),
+        ] {
+            if let Some(value) = config.get(key) {
+                *mode = parse_mode(value).ok_or_else(|| invalid_setting(key, value))?;
+            }
+        }
+        Ok(settings)
+    }
+}
+
+fn invalid_setting(key: &str, value: &str) -> BackupError {
+    BackupError::InvalidSetting {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// Parses octal permission bits such as `600`, `0640` or `0o750`.
+fn parse_mode(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0o").unwrap_or(text);
+    u32::from_str_radix(digits, 8).ok().filter(|mode| *mode <= 0o7777)
+}
+
+/// Parses a lock timeout: seconds such as `30`, `30s`, `5m` or `1h`, `0` to
+/// fail at once, or `forever` to wait indefinitely, which yields `Some(None)`.
+pub fn parse_timeout(text: &str) -> Option<Option<Duration>> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("forever") || text.eq_ignore_ascii_case("wait") {
+        return Some(None);
+    }
+
+    let (digits, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => text.split_at(split),
+        None => (text, ""),
+    };
+    let scale = match unit.to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return None,
+    };
+    let seconds = digits.parse::<u64>().ok()?.checked_mul(scale)?;
+    Some(Some(Duration::from_secs(seconds)))
+}
+
+/// Parses an age such as `36h`, `30d`, `2w`, `6mo` (30-day months), `1y`
+/// (365 days) or a combination like `1y6mo`. A bare `m` is refused, since
+/// [`parse_timeout`] reads it as minutes.
+pub fn parse_age(text: &str) -> Option<TimeDelta> {
+    let text = text.trim().to_ascii_lowercase();
+    let mut rest = text.as_str();
+    if rest.is_empty() {
+        return None;
+    }
+    let mut hours: i64 = 0;
+    while !rest.is_empty() {
+        let split = rest.find(|c: char| !c.is_ascii_digit())?;
+        let (digits, tail) = rest.split_at(split);
+        let (scale, unit_len) = match tail.as_bytes() {
+            [b'm', b'o', ..] => (30 * 24, 2),
+            [b'h', ..] => (1, 1),
+            [b'd', ..] => (24, 1),
+            [b'w', ..] => (7 * 24, 1),
+            [b'y', ..] => (365 * 24, 1),
+            _ => return None,
+        };
+        hours = hours.checked_add(digits.parse::<i64>().ok()?.checked_mul(scale)?)?;
+        rest = &tail[unit_len..];
+    }
+    TimeDelta::try_hours(hours)
+}
+
+/// Parses an age as [`parse_age`] does, or `never` for `Some(None)`.
+fn parse_expiry(text: &str) -> Option<Option<TimeDelta>> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "never" | "none" | "off" => Some(None),
+        _ => parse_age(text).map(Some),
+    }
+}
+
+fn parse_bool(text: &str) -> Option<bool> {
+    match text.trim().to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses a byte count such as `512`, `64K`, `10M` or `2G` (powers of
+/// 1024). `0`, `none` and `unlimited` mean no limit and yield `Some(None)`.
+pub fn parse_size(text: &str) -> Option<Option<u64>> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("none") || text.eq_ignore_ascii_case("unlimited") {
+        return Some(None);
+    }
+
+    let (digits, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => text.split_at(split),
+        None => (text, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+
+    let bytes = digits.parse::<u64>().ok()?.checked_mul(1 << shift)?;
+    Some(if bytes == 0 { None } else { Some(bytes) })
+}
+
+/// Repository settings stored as `key = value` lines; `#` starts a comment.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    /// Value of `key`; when a key is repeated the last line wins.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => self.entries.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path).map_err(|e| BackupError::io(path, e))?;
+        Ok(Config::parse(&text))
+    }
+
+    fn parse(text: &str) -> Config {
+        let entries = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .collect();
+        Config { entries }
+    }
+
+    fn write(&self, path: &Path, settings: &Settings) -> Result<()> {
+        let mut text = String::from("# safe_backup repository\n");
+        for (key, value) in &self.entries {
+            text.push_str(&format!("{} = {}\n", key, value));
+        }
+        write_atomically(path, settings.file_mode, settings.durability, |file, tmp_path| {
+            file.write_all(text.as_bytes()).map_err(|e| BackupError::io(tmp_path, e))
+        })
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::Path;
+
+use crate::audit::Record;
+
+/// The local syslog socket.
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The journal's native protocol socket.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// `APP-NAME` in syslog and `SYSLOG_IDENTIFIER` in the journal.
+const IDENTIFIER: &str = "safe_backup";
+
+/// SD-ID of the structured data element; 32473 is the enterprise number
+/// RFC 5612 reserves for documentation and private use.
+const SD_ID: &str = "safe_backup@32473";
+
+/// Facility used unless `syslog_facility` says otherwise: `user`.
+pub(crate) const DEFAULT_FACILITY: u8 = 1;
+
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+
+/// Where audit entries go (`log_sinks`). Any combination can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogSink {
+    /// The audit log file, rotated and hash-chained.
+    File,
+    /// The local syslog daemon, over `/dev/log`, as RFC 5424 messages.
+    Syslog,
+    /// The systemd journal, over its native protocol, with every field of
+    /// the entry as a journal field.
+    Journald,
+}
+
+impl LogSink {
+    /// Parses a comma-separated list such as `file, journald`.
+    pub fn parse_list(text: &str) -> Option<Vec<LogSink>> {
+        let mut sinks = Vec::new();
+        for name in text.split(',').map(str::trim) {
+            let sink = match name.to_ascii_lowercase().as_str() {
+                "file" => LogSink::File,
+                "syslog" => LogSink::Syslog,
+                "journald" | "journal" => LogSink::Journald,
+                _ => return None,
+            };
+            if !sinks.contains(&sink) {
+                sinks.push(sink);
+            }
+        }
+        Some(sinks)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogSink::File => "file",
+            LogSink::Syslog => "syslog",
+            LogSink::Journald => "journald",
+        }
+    }
+}
+
+impl fmt::Display for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses a syslog facility name (`user`, `daemon`, `auth`, `authpriv`,
+/// `local0` to `local7`) into its code.
+pub fn parse_facility(text: &str) -> Option<u8> {
+    let text = text.trim().to_ascii_lowercase();
+    match text.as_str() {
+        "user" => Some(1),
+        "daemon" => Some(3),
+        "auth" => Some(4),
+        "authpriv" => Some(10),
+        _ => {
+            let n: u8 = text.strip_prefix("local")?.parse().ok()?;
+            (n <= 7).then_some(16 + n)
+        }
+    }
+}
+
+/// Longest `HOSTNAME` and `MSGID` RFC 5424 allows.
+const MAX_HOSTNAME: usize = 255;
+const MAX_MSGID: usize = 32;
+
+/// Sends `record` to the local syslog daemon as an RFC 5424 message, its
+/// fields in a structured data element.
+pub(crate) fn send_syslog(record: &Record, facility: u8) -> io::Result<()> {
+    let message = syslog_message(record, facility);
+    let socket = UnixDatagram::unbound()?;
+    match socket.send_to(message.as_bytes(), SYSLOG_SOCKET) {
+        // Some daemons listen on a stream socket, one message per line.
+        Err(e) if e.raw_os_error() == Some(libc::EPROTOTYPE) => UnixStream::connect(SYSLOG_SOCKET)
+            .and_then(|mut stream| stream.write_all(format!("{}\n", message.replace('\n', " ")).as_bytes())),
+        sent => sent.map(|_| ()),
+    }
+    .map_err(|e| socket_error(SYSLOG_SOCKET, e))
+}
+
+fn syslog_message(record: &Record, facility: u8) -> String {
+    let pri = u16::from(facility) * 8 + u16::from(severity(record));
+    let mut data = format!("[{}", SD_ID);
+    for (name, value) in fields(record) {
+        data.push_str(&format!(" {}=\"{}\"", name, escape_param(&value)));
+    }
+    data.push(']');
+    format!(
+        "<{}>1 {} {} {} {} {} {} {}",
+        pri,
+        record.timestamp,
+        header_field(&record.hostname, MAX_HOSTNAME),
+        IDENTIFIER,
+        record.pid,
+        header_field(record.operation, MAX_MSGID),
+        data,
+        record.message
+    )
+}
+
+/// A header field as RFC 5424 allows it: printable ASCII without spaces,
+/// anything else replaced by `_`, at most `max` characters, and `-` when
+/// there is nothing.
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value.chars().take(max).map(|c| if c.is_ascii_graphic() { c } else { '_' }).collect();
+    if field.is_empty() { "-".to_string() } else { field }
+}
+
+/// Sends `record` to the journal over its native protocol. The journal adds
+/// the trusted `_PID`, `_UID` and `_HOSTNAME` fields itself.
+pub(crate) fn send_journald(record: &Record) -> io::Result<()> {
+    UnixDatagram::unbound()?
+        .send_to(&journald_datagram(record), Path::new(JOURNALD_SOCKET))
+        .map(|_| ())
+        .map_err(|e| socket_error(JOURNALD_SOCKET, e))
+}
+
+fn journald_datagram(record: &Record) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    let mut field = |name: &str, value: &str| {
+        datagram.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            // Binary-safe form: the name, a newline, the length as a
+            // little-endian u64, then the value.
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            datagram.push(b'=');
+        }
+        datagram.extend_from_slice(value.as_bytes());
+        datagram.push(b'\n');
+    };
+    field("MESSAGE", &record.message);
+    field("PRIORITY", &severity(record).to_string());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER);
+    field("SAFE_BACKUP_OPERATION", record.operation);
+    field("SAFE_BACKUP_TIMESTAMP", &record.timestamp);
+    for (name, value) in fields(record) {
+        field(&format!("SAFE_BACKUP_{}", name.to_ascii_uppercase()), &value);
+    }
+    datagram
+}
+
+/// Names the socket in `e`, so a missing daemon is easy to tell apart.
+fn socket_error(socket: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", socket, e))
+}
+
+/// `notice` for an operation that went through, `warning` otherwise.
+fn severity(record: &Record) -> u8 {
+    if record.result == "success" { SEVERITY_NOTICE } else { SEVERITY_WARNING }
+}
+
+/// The fields of `record` beyond its message, operation and time; those
+/// that do not apply are left out.
+fn fields(record: &Record) -> Vec<(&'static str, String)> {
+    let mut fields = vec![("target", record.target.clone()), ("result", record.result.to_string())];
+    if let Some(reason) = &record.reason {
+        fields.push(("reason", reason.clone()));
+    }
+    if let Some(bytes) = record.bytes {
+        fields.push(("bytes", bytes.to_string()));
+    }
+    if let Some(digest) = &record.digest {
+        fields.push(("digest", digest.clone()));
+    }
+    if let Some(generation) = record.generation {
+        fields.push(("generation", generation.to_string()));
+    }
+    fields.push(("user", record.user.clone()));
+    fields.push(("uid", record.uid.to_string()));
+    fields
+}
+
+/// Escapes `"`, `\` and `]` in an SD-PARAM value, as RFC 5424 requires.
+fn escape_param(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            timestamp: "2025-07-20T12:00:00.000+02:00".to_string(),
+            operation: "backup",
+            target: "/home/alice/a \"b\".txt".to_string(),
+            result: "success",
+            reason: None,
+            bytes: Some(5),
+            digest: None,
+            generation: Some(3),
+            message: "Performed backup on a \"b\".txt".to_string(),
+            user: "alice".to_string(),
+            uid: 1000,
+            pid: 42,
+            hostname: "host".to_string(),
+        }
+    }
+
+    #[test]
+    fn syslog_message_carries_every_field() {
+        assert_eq!(
+            syslog_message(&record(), DEFAULT_FACILITY),
+            concat!(
+                "<13>1 2025-07-20T12:00:00.000+02:00 host safe_backup 42 backup ",
+                r#"[safe_backup@32473 target="/home/alice/a \"b\".txt" result="success" bytes="5" generation="3" "#,
+                r#"user="alice" uid="1000"] Performed backup on a "b".txt"#
+            )
+        );
+
+        let refused = Record {
+            result: "failure",
+            reason: Some("not found".to_string()),
+            bytes: None,
+            generation: None,
+            ..record()
+        };
+        let message = syslog_message(&refused, 20);
+        assert!(message.starts_with("<164>1 "), "{}", message);
+        assert!(message.contains(r#"result="failure" reason="not found" user="#), "{}", message);
+    }
+
+    #[test]
+    fn syslog_header_fields_are_printable_ascii() {
+        let header = |record: &Record| syslog_message(record, 1).split(' ').take(6).collect::<Vec<_>>().join(" ");
+
+        let spaced = Record {
+            operation: "log verify",
+            hostname: String::new(),
+            ..record()
+        };
+        assert_eq!(header(&spaced), "<13>1 2025-07-20T12:00:00.000+02:00 - safe_backup 42 log_verify");
+
+        let odd = Record {
+            operation: "b\u{e4}ck\tup-with-a-name-much-longer-than-thirty-two",
+            hostname: "h\u{f6}st".to_string(),
+            ..record()
+        };
+        assert_eq!(
+            header(&odd),
+            "<13>1 2025-07-20T12:00:00.000+02:00 h_st safe_backup 42 b_ck_up-with-a-name-much-longer-"
+        );
+        assert_eq!(header_field("", MAX_MSGID), "-");
+        assert_eq!(header_field(&"x".repeat(40), MAX_MSGID).len(), 32);
+    }
+
+    #[test]
+    fn journald_datagram_holds_one_field_per_line() {
+        let text = String::from_utf8(journald_datagram(&record())).unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                "MESSAGE=Performed backup on a \"b\".txt\n",
+                "PRIORITY=5\n",
+                "SYSLOG_IDENTIFIER=safe_backup\n",
+                "SAFE_BACKUP_OPERATION=backup\n",
+                "SAFE_BACKUP_TIMESTAMP=2025-07-20T12:00:00.000+02:00\n",
+                "SAFE_BACKUP_TARGET=/home/alice/a \"b\".txt\n",
+                "SAFE_BACKUP_RESULT=success\n",
+                "SAFE_BACKUP_BYTES=5\n",
+                "SAFE_BACKUP_GENERATION=3\n",
+                "SAFE_BACKUP_USER=alice\n",
+                "SAFE_BACKUP_UID=1000\n",
+            )
+        );
+    }
+
+    #[test]
+    fn journald_multiline_value_is_length_prefixed() {
+        let multiline = Record {
+            message: "two\nlines".to_string(),
+            result: "failure",
+            ..record()
+        };
+        let datagram = journald_datagram(&multiline);
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\nPRIORITY=4\n");
+        assert!(datagram.starts_with(&expected));
+    }
+
+    #[test]
+    fn sd_param_values_escape_quotes_backslashes_and_brackets() {
+        assert_eq!(escape_param("plain value"), "plain value");
+        assert_eq!(escape_param(r#"a"b\c]d"#), r#"a\"b\\c\]d"#);
+        assert_eq!(escape_param("[x]"), r"[x\]");
+        assert_eq!(escape_param(""), "");
+    }
+}
@@ -0,0 +1,573 @@
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, TimeDelta};
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{audited, log_action, AuditEntry};
+use crate::chunks::{is_chunk_id, ChunkRef, ChunkStore, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+use crate::confirm::{Confirm, Prompt};
+use crate::crypt::{Encryption, SealingKey};
+use crate::digest::HashingReader;
+use crate::error::{BackupError, Result};
+use crate::files::{create_dir_all, sync_renamed, write_atomically, PRIVATE_FILE_MODE};
+use crate::lock::{FileLock, LockMode, NameLocks};
+use crate::meta::FileMetadata;
+use crate::ops::read_chunks;
+use crate::repository::Repository;
+use crate::validate_filename;
+
+/// The deleted file itself, inside its entry's directory.
+const DATA_FILE: &str = "data";
+/// The entry's [`TrashEntry`] record.
+const RECORD_FILE: &str = "entry.json";
+/// Lock on the trash as a whole; entry directories are numbers, so it
+/// never clashes with one.
+const LOCK_FILE: &str = "lock";
+
+/// A file [`delete`](crate::delete) moved to the repository's trash,
+/// described by `trash/<id>/entry.json` next to the file in `data`.
+///
+/// The record is written before the file is moved in, so an entry without
+/// its data is a delete that never finished, and is not listed. When the
+/// repository encrypts, a regular file goes to the chunk store instead,
+/// sealed like a backup, and its record is written once every chunk is
+/// stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    /// Increasing with every delete; derived from the directory name.
+    #[serde(skip)]
+    pub id: u64,
+    /// The entry's directory; derived, not stored in the record.
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// The name `undelete` takes.
+    pub name: String,
+    /// Absolute path the file was deleted from.
+    pub original: PathBuf,
+    pub deleted: DateTime<Local>,
+    pub size: u64,
+    /// Mode, ownership, times and extended attributes of a regular file,
+    /// unless metadata is not preserved; put back on undelete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    /// Present when the file was stored encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// The chunks holding an encrypted file, in order; `None` when the file
+    /// itself is in `data`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<ChunkRef>>,
+    /// Hex SHA-256 of an encrypted file's data, checked on undelete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl TrashEntry {
+    /// When trash expiry removes the entry, given the repository's
+    /// [`Settings::trash_expiry`](crate::Settings::trash_expiry).
+    pub fn expires(&self, expiry: Option<TimeDelta>) -> Option<DateTime<Local>> {
+        expiry.map(|expiry| self.deleted + expiry)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndeleteReport {
+    pub target: PathBuf,
+    /// The entry the file came back from, now gone from the trash.
+    pub entry: TrashEntry,
+    /// Metadata that could not be put back.
+    pub warnings: Vec<String>,
+}
+
+/// Every file in the trash, in the order they were deleted.
+pub fn list_trash(repo: &Repository) -> Result<Vec<TrashEntry>> {
+    let _lock = lock(repo, LockMode::Shared)?;
+    Ok(read_entries(repo)?.into_iter().filter_map(|(_, entry)| entry).collect())
+}
+
+/// Moves the newest deleted file named `filename` out of the trash and back
+/// into place, putting its recorded metadata back unless
+/// [`Settings::preserve_metadata`](crate::Settings::preserve_metadata) is
+/// off. An existing `filename` is only replaced if the [`Confirm`] policy
+/// agrees; older deleted copies stay in the trash.
+pub fn undelete(repo: &Repository, filename: &str, confirm: &mut dyn Confirm) -> Result<UndeleteReport> {
+    audited(repo, "undelete", Path::new(filename), run_undelete(repo, filename, Path::new(filename), confirm))
+}
+
+/// Undeletes the newest entry named `filename` to `target`.
+fn run_undelete(
+    repo: &Repository,
+    filename: &str,
+    target: &Path,
+    confirm: &mut dyn Confirm,
+) -> Result<UndeleteReport> {
+    validate_filename(filename)?;
+    let _locks = NameLocks::acquire(repo, filename)?;
+    let _lock = lock(repo, LockMode::Exclusive)?;
+
+    let entry = read_entries(repo)?
+        .into_iter()
+        .filter_map(|(_, entry)| entry)
+        .rfind(|entry| entry.name == filename)
+        .ok_or_else(|| BackupError::NotInTrash(filename.to_string()))?;
+
+    let _target = FileLock::existing(repo, target, LockMode::Exclusive)?;
+    if fs::symlink_metadata(target).is_ok() && !confirm.confirm(&Prompt::OverwriteTarget { target }) {
+        return Err(BackupError::Cancelled);
+    }
+
+    match &entry.chunks {
+        Some(chunks) => write_chunks(repo, &entry, chunks, target)?,
+        None => move_file(repo, &entry.path.join(DATA_FILE), target)?,
+    }
+    let warnings = match &entry.metadata {
+        Some(metadata) if repo.settings().preserve_metadata => metadata.apply(target)?,
+        _ => Vec::new(),
+    };
+    fs::remove_dir_all(&entry.path).map_err(|e| BackupError::io(&entry.path, e))?;
+
+    let message = format!("Performed undelete on {}", filename);
+    log_action(repo, &AuditEntry {
+        bytes: Some(entry.size),
+        ..AuditEntry::success("undelete", target, message)
+    })
+    .map_err(BackupError::Log)?;
+
+    Ok(UndeleteReport {
+        target: target.to_path_buf(),
+        entry,
+        warnings,
+    })
+}
+
+/// Moves the file at `path`, deleted under the name `filename`, into a new
+/// trash entry. Directories are refused, as they always were by delete.
+/// With a key configured a regular file is encrypted into the chunk store
+/// and then removed, so the trash never holds its plaintext.
+pub(crate) fn put(repo: &Repository, filename: &str, path: &Path) -> Result<TrashEntry> {
+    let _lock = lock(repo, LockMode::Exclusive)?;
+    let settings = repo.settings();
+    let file_type = fs::symlink_metadata(path).map_err(|e| BackupError::io(path, e))?;
+    if file_type.is_dir() {
+        return Err(BackupError::io(path, io::Error::from(io::ErrorKind::IsADirectory)));
+    }
+    // A symlink is moved as it is; its target's attributes are not its own.
+    let metadata = (settings.preserve_metadata && file_type.is_file())
+        .then(|| FileMetadata::capture(path))
+        .transpose()?;
+
+    let (id, dir) = claim(repo)?;
+    let mut entry = TrashEntry {
+        id,
+        path: dir.clone(),
+        name: filename.to_string(),
+        original: std::path::absolute(path).map_err(|e| BackupError::io(path, e))?,
+        deleted: Local::now(),
+        size: file_type.len(),
+        metadata,
+        encryption: None,
+        chunks: None,
+        sha256: None,
+    };
+    let moved = match &settings.key {
+        Some(source) if file_type.is_file() => {
+            // Chunks left behind by a failure are collected by the next prune.
+            repo.kdf_salt()
+                .and_then(|salt| SealingKey::create(source, salt, repo.root()))
+                .and_then(|(key, encryption)| {
+                    let (chunks, size, sha256) = store_chunks(repo, key, path)?;
+                    entry.size = size;
+                    entry.encryption = Some(encryption);
+                    entry.chunks = Some(chunks);
+                    entry.sha256 = Some(sha256);
+                    write_record(repo, &entry)
+                })
+                .and_then(|()| fs::remove_file(path).map_err(|e| BackupError::io(path, e)))
+                .and_then(|()| sync_renamed(path, settings.durability))
+        }
+        _ => write_record(repo, &entry).and_then(|()| move_file(repo, path, &dir.join(DATA_FILE))),
+    };
+    if let Err(e) = moved {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+    }
+    Ok(entry)
+}
+
+/// Chunks the file at `path` into the chunk store, sealed with `key`, and
+/// returns its chunk list, size and hex SHA-256.
+fn store_chunks(repo: &Repository, key: SealingKey, path: &Path) -> Result<(Vec<ChunkRef>, u64, String)> {
+    let store = ChunkStore::new(repo, repo.settings().compression, Some(key));
+    let mut input = HashingReader::new(File::open(path).map_err(|e| BackupError::io(path, e))?);
+    let mut chunks = Vec::new();
+    let mut size = 0;
+    for data in StreamCDC::new(&mut input, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let data = data.map_err(|e| BackupError::io(path, e.into()))?;
+        let (chunk, _) = store.put(&data.data)?;
+        size += chunk.size;
+        chunks.push(chunk);
+    }
+    Ok((chunks, size, input.finish()))
+}
+
+/// Decrypts the chunks of `entry` into a new file that then takes the place
+/// of `target`, once its size and digest match the entry's.
+fn write_chunks(repo: &Repository, entry: &TrashEntry, chunks: &[ChunkRef], target: &Path) -> Result<()> {
+    let record_path = entry.path.join(RECORD_FILE);
+    let (Some(encryption), Some(expected)) = (&entry.encryption, &entry.sha256) else {
+        return Err(BackupError::Tampered(record_path));
+    };
+    let source = repo.settings().key.as_ref().ok_or_else(|| BackupError::KeyRequired(record_path.clone()))?;
+    let key = SealingKey::open(source, encryption, &record_path)?;
+    let store = ChunkStore::new(repo, repo.settings().compression, Some(key));
+
+    write_atomically(target, PRIVATE_FILE_MODE, repo.settings().durability, |file, tmp_path| {
+        let (copied, digest) = read_chunks(&store, chunks, file, tmp_path)?;
+        if copied != entry.size || digest != *expected {
+            return Err(BackupError::ChecksumMismatch {
+                path: record_path.clone(),
+                expected: expected.clone(),
+                actual: digest,
+            });
+        }
+        Ok(())
+    })
+}
+
+/// The chunks of every encrypted file left in the trash once `expired` is
+/// gone, which a prune must keep.
+pub(crate) fn chunks_in_use(repo: &Repository, expired: &[TrashEntry]) -> Result<Vec<ChunkRef>> {
+    Ok(list_trash(repo)?
+        .into_iter()
+        .filter(|entry| !expired.iter().any(|gone| gone.id == entry.id))
+        .flat_map(|entry| entry.chunks.unwrap_or_default())
+        .collect())
+}
+
+/// Removes every entry deleted longer ago than the repository's
+/// `trash_expiry`, and what unfinished deletes left behind, and returns the
+/// entries removed. With `dry_run` they are only returned.
+pub(crate) fn expire(repo: &Repository, now: DateTime<Local>, dry_run: bool) -> Result<Vec<TrashEntry>> {
+    let Some(expiry) = repo.settings().trash_expiry else {
+        return Ok(Vec::new());
+    };
+    let _lock = lock(repo, LockMode::Exclusive)?;
+
+    let mut expired = Vec::new();
+    for (dir, entry) in read_entries(repo)? {
+        match entry {
+            Some(entry) if entry.deleted + expiry <= now => expired.push(entry),
+            Some(_) => continue,
+            // Held exclusively by every delete, so no other one is running.
+            None if dry_run => continue,
+            None => fs::remove_dir_all(&dir).map_err(|e| BackupError::io(&dir, e))?,
+        }
+    }
+    if dry_run || expired.is_empty() {
+        return Ok(expired);
+    }
+
+    for entry in &expired {
+        fs::remove_dir_all(&entry.path).map_err(|e| BackupError::io(&entry.path, e))?;
+    }
+    let freed: u64 = expired.iter().map(|entry| entry.size).sum();
+    let message = format!("Performed expire: removed {} files from the trash, freed {} bytes", expired.len(), freed);
+    log_action(repo, &AuditEntry {
+        bytes: Some(freed),
+        ..AuditEntry::success("expire", &repo.trash_dir(), message)
+    })
+    .map_err(BackupError::Log)?;
+    Ok(expired)
+}
+
+/// Locks the trash against changes: exclusively to change it, shared to
+/// read it.
+fn lock(repo: &Repository, mode: LockMode) -> Result<FileLock> {
+    let settings = repo.settings();
+    let dir = repo.trash_dir();
+    create_dir_all(&dir, settings.dir_mode, settings.durability)?;
+    FileLock::lock_file(repo, &dir.join(LOCK_FILE), mode)
+}
+
+/// Every entry directory in the trash, sorted by id, with its entry when
+/// both its record and its data, in `data` or the chunk store, are there.
+fn read_entries(repo: &Repository) -> Result<Vec<(PathBuf, Option<TrashEntry>)>> {
+    let trash = repo.trash_dir();
+    let mut dirs = Vec::new();
+    for dir_entry in fs::read_dir(&trash).map_err(|e| BackupError::io(&trash, e))? {
+        let dir_entry = dir_entry.map_err(|e| BackupError::io(&trash, e))?;
+        if let Some(id) = parse_id(&dir_entry.file_name().to_string_lossy()) {
+            dirs.push((id, dir_entry.path()));
+        }
+    }
+    dirs.sort_by_key(|(id, _)| *id);
+
+    let mut entries = Vec::with_capacity(dirs.len());
+    for (id, dir) in dirs {
+        let entry = read_record(id, &dir)?
+            .filter(|entry| entry.chunks.is_some() || fs::symlink_metadata(dir.join(DATA_FILE)).is_ok());
+        entries.push((dir, entry));
+    }
+    Ok(entries)
+}
+
+/// Reads the record of entry `id` in `dir`; `None` if it was never written.
+fn read_record(id: u64, dir: &Path) -> Result<Option<TrashEntry>> {
+    let record_path = dir.join(RECORD_FILE);
+    let text = match fs::read_to_string(&record_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        text => text.map_err(|e| BackupError::io(&record_path, e))?,
+    };
+    let invalid = |e: String| BackupError::io(&record_path, io::Error::new(io::ErrorKind::InvalidData, e));
+    let mut entry: TrashEntry = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    if let Some(chunk) = entry.chunks.iter().flatten().find(|c| !is_chunk_id(&c.id) || c.size > MAX_CHUNK_SIZE as u64) {
+        return Err(invalid(format!("invalid chunk {:?}", chunk.id)));
+    }
+    entry.id = id;
+    entry.path = dir.to_path_buf();
+    Ok(Some(entry))
+}
+
+fn write_record(repo: &Repository, entry: &TrashEntry) -> Result<()> {
+    let record_path = entry.path.join(RECORD_FILE);
+    let json = serde_json::to_string_pretty(entry).map_err(|e| BackupError::io(&record_path, io::Error::other(e)))?;
+    let settings = repo.settings();
+    write_atomically(&record_path, settings.file_mode, settings.durability, |file, tmp_path| {
+        file.write_all(json.as_bytes()).map_err(|e| BackupError::io(tmp_path, e))
+    })
+}
+
+/// Creates the directory for the next entry and returns its id and path.
+fn claim(repo: &Repository) -> Result<(u64, PathBuf)> {
+    let settings = repo.settings();
+    let mut id = read_entries(repo)?.last().and_then(|(dir, _)| dir.file_name()?.to_str()?.parse().ok()).unwrap_or(0);
+    loop {
+        id += 1;
+        let dir = repo.trash_dir().join(id.to_string());
+        match DirBuilder::new().mode(settings.dir_mode).create(&dir) {
+            Ok(()) => return Ok((id, dir)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(BackupError::io(&dir, e)),
+        }
+    }
+}
+
+/// Renames `from` to `to`, replacing whatever is there. Between filesystems
+/// a regular file is copied instead, then removed.
+fn move_file(repo: &Repository, from: &Path, to: &Path) -> Result<()> {
+    let durability = repo.settings().durability;
+    match fs::rename(from, to) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) && from.is_file() && !from.is_symlink() => {
+            write_atomically(to, repo.settings().file_mode, durability, |file, tmp_path| {
+                let mut source = File::open(from).map_err(|e| BackupError::io(from, e))?;
+                io::copy(&mut source, file).map(|_| ()).map_err(|e| BackupError::io(tmp_path, e))
+            })?;
+            fs::remove_file(from).map_err(|e| BackupError::io(from, e))?;
+        }
+        Err(e) => return Err(BackupError::io(from, e)),
+    }
+    sync_renamed(to, durability)?;
+    sync_renamed(from, durability)
+}
+
+/// Parses an entry directory name, a plain decimal id.
+fn parse_id(name: &str) -> Option<u64> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    name.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::confirm::{AssumeNo, AssumeYes};
+    use crate::crypt::KeySource;
+
+    /// A new repository in a fresh directory, logging to `audit.log` inside
+    /// it. Deleted files are made in `work/` beside it.
+    fn scratch_repo() -> Repository {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "safe_backup_trash_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut repo = Repository::init(&dir).unwrap();
+        repo.settings_mut().log_path = Some(PathBuf::from("audit.log"));
+        fs::create_dir(dir.join("work")).unwrap();
+        repo
+    }
+
+    fn work_file(repo: &Repository, name: &str, contents: &str) -> PathBuf {
+        let path = repo.root().join("work").join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn undelete_to(repo: &Repository, name: &str, confirm: &mut dyn Confirm) -> Result<UndeleteReport> {
+        run_undelete(repo, name, &repo.root().join("work").join(name), confirm)
+    }
+
+    #[test]
+    fn undelete_puts_data_and_metadata_back() {
+        let repo = scratch_repo();
+        let path = work_file(&repo, "notes.txt", "some notes\n");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+        let entry = put(&repo, "notes.txt", &path).unwrap();
+        assert!(!path.exists());
+        assert_eq!((entry.name.as_str(), entry.size), ("notes.txt", 11));
+        assert_eq!(entry.original, path);
+        assert!(entry.path.join(DATA_FILE).exists() && entry.chunks.is_none());
+        assert_eq!(list_trash(&repo).unwrap(), vec![entry.clone()]);
+
+        let report = undelete_to(&repo, "notes.txt", &mut AssumeNo).unwrap();
+        assert_eq!(report.entry, entry);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "some notes\n");
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert!(list_trash(&repo).unwrap().is_empty());
+        assert!(!entry.path.exists());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn undelete_takes_the_newest_entry_of_a_name() {
+        let repo = scratch_repo();
+        let path = work_file(&repo, "notes.txt", "old");
+        let old = put(&repo, "notes.txt", &path).unwrap();
+        work_file(&repo, "notes.txt", "newer");
+        let newer = put(&repo, "notes.txt", &path).unwrap();
+        put(&repo, "other.txt", &work_file(&repo, "other.txt", "other")).unwrap();
+        assert!(newer.id > old.id);
+
+        assert_eq!(undelete_to(&repo, "notes.txt", &mut AssumeNo).unwrap().entry, newer);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+        let ids: Vec<u64> = list_trash(&repo).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![old.id, old.id + 2]);
+
+        // The older copy only replaces the file once confirmed.
+        assert!(matches!(undelete_to(&repo, "notes.txt", &mut AssumeNo), Err(BackupError::Cancelled)));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "newer");
+        assert_eq!(undelete_to(&repo, "notes.txt", &mut AssumeYes).unwrap().entry, old);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn unknown_name_is_not_in_trash() {
+        let repo = scratch_repo();
+        put(&repo, "notes.txt", &work_file(&repo, "notes.txt", "notes")).unwrap();
+
+        let error = undelete_to(&repo, "other.txt", &mut AssumeYes).unwrap_err();
+        assert!(matches!(&error, BackupError::NotInTrash(name) if name == "other.txt"));
+        assert_eq!(error.exit_code(), 4);
+        assert_eq!(list_trash(&repo).unwrap().len(), 1);
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn entries_expire_after_trash_expiry() {
+        let mut repo = scratch_repo();
+        let entry = put(&repo, "notes.txt", &work_file(&repo, "notes.txt", "notes")).unwrap();
+        let later = entry.deleted + TimeDelta::days(3650);
+
+        repo.settings_mut().trash_expiry = None;
+        assert_eq!(entry.expires(None), None);
+        assert!(expire(&repo, later, false).unwrap().is_empty());
+        assert_eq!(list_trash(&repo).unwrap().len(), 1);
+
+        repo.settings_mut().trash_expiry = Some(TimeDelta::minutes(1));
+        let expires = entry.deleted + TimeDelta::minutes(1);
+        assert_eq!(entry.expires(Some(TimeDelta::minutes(1))), Some(expires));
+        assert!(expire(&repo, expires - TimeDelta::seconds(1), false).unwrap().is_empty());
+        assert_eq!(expire(&repo, expires, true).unwrap(), vec![entry.clone()]);
+        assert!(entry.path.exists());
+        assert_eq!(expire(&repo, expires, false).unwrap(), vec![entry.clone()]);
+        assert!(!entry.path.exists());
+        assert!(list_trash(&repo).unwrap().is_empty());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn unfinished_delete_is_not_listed_and_is_cleared_by_expiry() {
+        let repo = scratch_repo();
+        let entry = put(&repo, "notes.txt", &work_file(&repo, "notes.txt", "notes")).unwrap();
+        fs::remove_file(entry.path.join(DATA_FILE)).unwrap();
+
+        assert!(list_trash(&repo).unwrap().is_empty());
+        assert!(expire(&repo, entry.deleted, true).unwrap().is_empty());
+        assert!(entry.path.exists());
+        expire(&repo, entry.deleted, false).unwrap();
+        assert!(!entry.path.exists());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn encrypted_repository_keeps_no_plaintext_in_the_trash() {
+        let mut repo = scratch_repo();
+        let key_path = repo.root().join("trash.key");
+        fs::write(&key_path, [3u8; 32]).unwrap();
+        repo.settings_mut().key = Some(KeySource::KeyFile(key_path));
+        let contents = "a secret line\n".repeat(1000);
+        let path = work_file(&repo, "notes.txt", &contents);
+
+        let entry = put(&repo, "notes.txt", &path).unwrap();
+        assert!(!path.exists());
+        assert!(!entry.path.join(DATA_FILE).exists());
+        assert!(entry.encryption.is_some() && entry.sha256.is_some());
+        for chunk in entry.chunks.as_ref().unwrap() {
+            let stored = fs::read(repo.chunk_path(&chunk.id)).unwrap();
+            assert!(!stored.windows(6).any(|w| w == b"secret"));
+        }
+        assert_eq!(list_trash(&repo).unwrap(), vec![entry.clone()]);
+
+        let mut without_key = repo.clone();
+        without_key.settings_mut().key = None;
+        assert!(matches!(
+            undelete_to(&without_key, "notes.txt", &mut AssumeYes),
+            Err(BackupError::KeyRequired(_))
+        ));
+        assert!(!path.exists());
+
+        undelete_to(&repo, "notes.txt", &mut AssumeYes).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        assert!(list_trash(&repo).unwrap().is_empty());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+
+    #[test]
+    fn prune_keeps_the_chunks_of_encrypted_files_until_they_expire() {
+        let mut repo = scratch_repo();
+        let key_path = repo.root().join("trash.key");
+        fs::write(&key_path, [3u8; 32]).unwrap();
+        repo.settings_mut().key = Some(KeySource::KeyFile(key_path));
+        let entry = put(&repo, "notes.txt", &work_file(&repo, "notes.txt", "notes")).unwrap();
+        let chunk = repo.chunk_path(&entry.chunks.as_ref().unwrap()[0].id);
+
+        let report = crate::prune(&repo, false).unwrap();
+        assert_eq!((report.removed_chunks, report.expired.len()), (0, 0));
+        assert!(chunk.exists());
+
+        repo.settings_mut().trash_expiry = Some(TimeDelta::zero());
+        let report = crate::prune(&repo, false).unwrap();
+        assert_eq!((report.removed_chunks, report.expired), (1, vec![entry]));
+        assert!(!chunk.exists());
+        fs::remove_dir_all(repo.root()).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::chunks::ChunkRef;
+use crate::digest::to_hex;
+use crate::error::{BackupError, Result};
+use crate::meta::FileMetadata;
+
+/// What a [`TreeEntry`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+        }
+    }
+}
+
+/// One file or subdirectory of a directory snapshot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeEntry {
+    /// Path below the snapshot root, `/`-separated; only plain components.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of a file; 0 for a directory.
+    #[serde(default)]
+    pub size: u64,
+    /// Hex SHA-256 of a file's data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The chunks that make up a file, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkRef>,
+    /// Mode, ownership, times and extended attributes, unless the backup
+    /// was made without them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+}
+
+/// A file or directory found under a directory being backed up.
+pub(crate) struct Found {
+    /// Path below the root, `/`-separated.
+    pub(crate) relative: String,
+    /// Path to read it from.
+    pub(crate) path: PathBuf,
+    pub(crate) kind: EntryKind,
+    /// Taken as the entry was found, before reading it could move its
+    /// access time.
+    pub(crate) metadata: Option<FileMetadata>,
+}
+
+/// Everything under a directory, parents before their contents and siblings
+/// in name order, plus what had to be left out.
+pub(crate) struct Scan {
+    pub(crate) found: Vec<Found>,
+    /// Symlinks, sockets, devices and names that are not UTF-8. Symlinks are
+    /// never followed, so a snapshot only ever holds what lies inside the
+    /// root.
+    pub(crate) skipped: Vec<PathBuf>,
+}
+
+/// Walks everything below `root`, capturing each entry's metadata on the
+/// way when `preserve` is set.
+pub(crate) fn scan(root: &Path, preserve: bool) -> Result<Scan> {
+    let mut scan = Scan {
+        found: Vec::new(),
+        skipped: Vec::new(),
+    };
+    walk(root, "", preserve, &mut scan)?;
+    Ok(scan)
+}
+
+fn walk(dir: &Path, prefix: &str, preserve: bool, scan: &mut Scan) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .map_err(|e| BackupError::io(dir, e))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            scan.skipped.push(path);
+            continue;
+        };
+        let relative = format!("{}{}", prefix, name);
+        let file_type = entry.file_type().map_err(|e| BackupError::io(&path, e))?;
+        let kind = if file_type.is_dir() {
+            EntryKind::Directory
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            scan.skipped.push(path);
+            continue;
+        };
+
+        let metadata = preserve.then(|| FileMetadata::capture(&path)).transpose()?;
+        scan.found.push(Found {
+            relative: relative.clone(),
+            path: path.clone(),
+            kind,
+            metadata,
+        });
+        if kind == EntryKind::Directory {
+            walk(&path, &format!("{}/", relative), preserve, scan)?;
+        }
+    }
+    Ok(())
+}
+
+/// Turns a recorded entry path into a relative path, refusing anything that
+/// could land outside the directory it is restored into: absolute paths,
+/// `..`, and empty or `.` components.
+pub(crate) fn entry_path(path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let safe = !path.is_empty()
+        && !path.contains('\0')
+        && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+        && relative.components().all(|c| matches!(c, Component::Normal(_)));
+
+    if safe {
+        Ok(relative.to_path_buf())
+    } else {
+        Err(BackupError::InvalidName(path.to_string()))
+    }
+}
+
+/// Digest standing for a whole directory snapshot: the SHA-256 of its
+/// listing, one NUL-separated record per entry with kind, size, file digest
+/// and path. Two snapshots share it exactly when they hold the same tree.
+pub(crate) struct TreeHasher(Sha256);
+
+impl TreeHasher {
+    pub(crate) fn new() -> Self {
+        TreeHasher(Sha256::new())
+    }
+
+    pub(crate) fn add(&mut self, kind: EntryKind, path: &str, size: u64, sha256: Option<&str>) {
+        let line = format!("{}\0{}\0{}\0{}\0", kind.as_str(), size, sha256.unwrap_or("-"), path);
+        self.0.update(line.as_bytes());
+    }
+
+    pub(crate) fn finish(self) -> String {
+        to_hex(&self.0.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_relative_paths_are_accepted() {
+        assert_eq!(entry_path("a").unwrap(), PathBuf::from("a"));
+        assert_eq!(entry_path("a/b/c.txt").unwrap(), PathBuf::from("a").join("b").join("c.txt"));
+        assert_eq!(entry_path("..a/b..").unwrap(), PathBuf::from("..a/b.."));
+    }
+
+    #[test]
+    fn paths_leaving_the_target_are_refused() {
+        for path in [
+            "", "/", "/etc/passwd", "..", "../a", "a/..", "a/../../b", ".", "./a", "a/.", "a//b", "a/", "/a", "a\0b",
+        ] {
+            assert!(
+                matches!(entry_path(path), Err(BackupError::InvalidName(p)) if p == path),
+                "{:?} was accepted",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::audit::{audited, log_action, AuditEntry};
+use crate::error::{BackupError, Result};
+use crate::generations::{read_record, record_paths, Generation};
+use crate::lock::{repository_lock, LockMode};
+use crate::ops::{read_generation, read_tree};
+use crate::repository::Repository;
+
+/// What [`verify`] found for one generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    /// Size and SHA-256 match the record.
+    Ok,
+    /// The record exists but the data file, or one of its chunks, is gone.
+    Missing,
+    /// A single-file generation is shorter on disk than recorded.
+    Truncated,
+    /// The data does not decode, or decodes to the wrong size or digest.
+    Corrupted,
+    /// The data file exists but could not be read.
+    Unreadable,
+    /// The generation record itself could not be parsed.
+    BadRecord,
+    /// Encrypted, and no matching key was supplied; only its size was
+    /// checked. Not counted as a problem.
+    Unverified,
+}
+
+impl VerifyStatus {
+    /// The name used in the JSON report.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Missing => "missing",
+            VerifyStatus::Truncated => "truncated",
+            VerifyStatus::Corrupted => "corrupted",
+            VerifyStatus::Unreadable => "unreadable",
+            VerifyStatus::BadRecord => "bad_record",
+            VerifyStatus::Unverified => "unverified",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyEntry {
+    pub name: String,
+    pub generation: u64,
+    pub status: VerifyStatus,
+    pub path: PathBuf,
+    pub expected_size: Option<u64>,
+    pub actual_size: Option<u64>,
+    pub expected_sha256: Option<String>,
+    pub actual_sha256: Option<String>,
+    /// Why the generation failed, when the sizes and digests alone do not
+    /// say.
+    pub detail: Option<String>,
+}
+
+/// Result of auditing a whole repository; serializes to the JSON report.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub repository: PathBuf,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+    pub checked: usize,
+    pub problems: usize,
+    pub entries: Vec<VerifyEntry>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems == 0
+    }
+}
+
+/// Decodes and re-hashes every generation in `repo` and compares it with
+/// its record.
+///
+/// Damage is reported in the returned [`VerifyReport`], not as an error;
+/// errors are reserved for failing to walk the repository at all. A summary
+/// line goes to the audit log.
+pub fn verify(repo: &Repository) -> Result<VerifyReport> {
+    audited(repo, "verify", repo.root(), run_verify(repo))
+}
+
+fn run_verify(repo: &Repository) -> Result<VerifyReport> {
+    let _lock = repository_lock(repo, LockMode::Shared)?;
+    let started = Local::now();
+    let mut entries = Vec::new();
+
+    for name in repo.backed_up_names()? {
+        let dir = repo.generations_dir(&name);
+        for (id, record_path) in record_paths(&dir)? {
+            entries.push(match read_record(&dir, id, &record_path) {
+                Ok(generation) => {
+                    let mut entry = VerifyEntry {
+                        name: name.clone(),
+                        generation: id,
+                        status: VerifyStatus::Ok,
+                        path: generation.path.clone(),
+                        expected_size: Some(generation.size),
+                        actual_size: None,
+                        expected_sha256: Some(generation.sha256.clone()),
+                        actual_sha256: None,
+                        detail: None,
+                    };
+                    check_generation(&generation, repo, &mut entry);
+                    entry
+                }
+                Err(e) => VerifyEntry {
+                    name: name.clone(),
+                    generation: id,
+                    status: VerifyStatus::BadRecord,
+                    path: record_path,
+                    expected_size: None,
+                    actual_size: None,
+                    expected_sha256: None,
+                    actual_sha256: None,
+                    detail: Some(e.to_string()),
+                },
+            });
+        }
+    }
+
+    let problems = entries
+        .iter()
+        .filter(|e| !matches!(e.status, VerifyStatus::Ok | VerifyStatus::Unverified))
+        .count();
+    let report = VerifyReport {
+        repository: repo.root().to_path_buf(),
+        started,
+        finished: Local::now(),
+        checked: entries.len(),
+        problems,
+        entries,
+    };
+
+    let message = format!(
+        "Performed verify on {}: {} generations checked, {} problems",
+        repo.root().display(),
+        report.checked,
+        report.problems
+    );
+    log_action(repo, &AuditEntry::success("verify", repo.root(), message)).map_err(BackupError::Log)?;
+
+    Ok(report)
+}
+
+/// Fills in `entry.status` for a generation whose record parsed.
+fn check_generation(generation: &Generation, repo: &Repository, entry: &mut VerifyEntry) {
+    let stored_ok = if generation.chunks.is_some() || generation.is_directory() {
+        check_chunks(generation, repo, entry)
+    } else {
+        check_single_file(generation, entry)
+    };
+    let Some(stored_ok) = stored_ok else {
+        return;
+    };
+
+    let read = if generation.is_directory() {
+        read_tree(repo, generation, None)
+    } else {
+        read_generation(repo, generation, &mut io::sink(), Path::new("-"))
+    };
+    match read {
+        Ok((size, sha256)) => {
+            entry.status = if !stored_ok || size != generation.size || sha256 != generation.sha256 {
+                VerifyStatus::Corrupted
+            } else {
+                VerifyStatus::Ok
+            };
+            entry.actual_size = Some(size);
+            entry.actual_sha256 = Some(sha256);
+        }
+        Err(e @ (BackupError::KeyRequired(_) | BackupError::WrongKey(_))) => {
+            entry.status = VerifyStatus::Unverified;
+            entry.detail = Some(e.to_string());
+        }
+        Err(e) => {
+            entry.status = VerifyStatus::Corrupted;
+            entry.detail = Some(e.to_string());
+        }
+    }
+}
+
+/// Checks that every chunk of a generation is present, so missing chunks
+/// are reported even when there is no key to decode the rest. Returns
+/// `None` once `entry` holds a verdict.
+fn check_chunks(generation: &Generation, repo: &Repository, entry: &mut VerifyEntry) -> Option<bool> {
+    let absent: Vec<&str> = generation
+        .all_chunks()
+        .filter(|chunk| !repo.chunk_path(&chunk.id).is_file())
+        .map(|chunk| chunk.id.as_str())
+        .collect();
+    if absent.is_empty() {
+        return Some(true);
+    }
+
+    entry.status = VerifyStatus::Missing;
+    let total = generation.all_chunks().count();
+    entry.detail = Some(format!("{} of {} chunks missing: {}", absent.len(), total, absent.join(", ")));
+    None
+}
+
+/// Compares the size of a `.bak` file with its record. Returns `None` once
+/// `entry` holds a verdict, else whether the size matched exactly.
+fn check_single_file(generation: &Generation, entry: &mut VerifyEntry) -> Option<bool> {
+    let stored = match fs::metadata(&generation.path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            entry.status = VerifyStatus::Missing;
+            return None;
+        }
+        Err(e) => {
+            entry.status = VerifyStatus::Unreadable;
+            entry.detail = Some(e.to_string());
+            return None;
+        }
+    };
+
+    let expected_stored = generation.stored_size.unwrap_or(generation.size);
+    if stored < expected_stored {
+        entry.status = VerifyStatus::Truncated;
+        entry.detail = Some(format!("{} of {} stored bytes present", stored, expected_stored));
+        return None;
+    }
+    Some(stored == expected_stored)
+}